@@ -2,10 +2,14 @@
 
 use super::*;
 use bincode;
+use cache::{Arrival, PictureCache};
 use futures::{SinkExt, StreamExt};
+use std::collections::{HashSet, VecDeque};
 use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
 use tokio::{
-    io::AsyncWriteExt,
+    io::{AsyncReadExt, AsyncWriteExt},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpStream,
@@ -13,14 +17,129 @@ use tokio::{
 };
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
+/// The location policy last received from the server, shared between sender and receiver.
+type SharedPolicy = Arc<Mutex<Option<LocationPolicy>>>;
+
+/// The upload token last received from the server, shared between sender and receiver.
+type SharedToken = Arc<Mutex<Option<String>>>;
+
+/// Where the receiver forwards answers to chunked uploads while one is running.
+type SharedUploadEvents = Arc<Mutex<Option<tokio::sync::mpsc::UnboundedSender<ToApp>>>>;
+
+/// The sending half of the connection, shared with the heartbeat task.
+type SharedWriter = Arc<SharedWriterInner>;
+type SharedWriterInner = tokio::sync::Mutex<FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>>;
+
+type SharedHeartbeat = Arc<Mutex<Heartbeat>>;
+
+/// How often a heartbeat ping is sent.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// If nothing at all arrives from the server for this long, the connection is
+/// considered dead and `TrainlappcommsReceiver::recv` fails.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Round trip times above this make for a poor connection.
+const POOR_RTT: Duration = Duration::from_millis(1500);
+
+/// How well the connection to the server is doing, judging by the heartbeats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionQuality {
+    /// No heartbeat was answered yet.
+    Unknown,
+    Good,
+    /// Pongs are slow or one went missing.
+    Poor,
+    /// Several pongs in a row went missing, the connection is probably dead.
+    Bad,
+}
+
+#[derive(Default)]
+struct Heartbeat {
+    next_nonce: u64,
+    /// pings that weren't answered yet, oldest first
+    pending: VecDeque<(u64, Instant)>,
+    /// smoothed round trip time
+    rtt: Option<Duration>,
+}
+
+impl Heartbeat {
+    fn ping(&mut self) -> u64 {
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        self.pending.push_back((nonce, Instant::now()));
+        nonce
+    }
+
+    fn pong(&mut self, nonce: u64) {
+        let Some(index) = self.pending.iter().position(|(n, _)| *n == nonce) else {
+            return;
+        };
+        let sample = self.pending[index].1.elapsed();
+        // older pings that weren't answered won't be anymore
+        self.pending.drain(..=index);
+        // smoothed like tcp does, so a single slow pong doesn't dominate
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt * 7 / 8 + sample / 8,
+            None => sample,
+        });
+    }
+
+    fn quality(&self) -> ConnectionQuality {
+        let missed = self
+            .pending
+            .iter()
+            .filter(|(_, sent)| sent.elapsed() > HEARTBEAT_INTERVAL)
+            .count();
+        match (missed, self.rtt) {
+            (0, None) => ConnectionQuality::Unknown,
+            (0, Some(rtt)) if rtt < POOR_RTT => ConnectionQuality::Good,
+            (0 | 1, _) => ConnectionQuality::Poor,
+            _ => ConnectionQuality::Bad,
+        }
+    }
+}
+
+fn lock(heartbeat: &SharedHeartbeat) -> MutexGuard<'_, Heartbeat> {
+    heartbeat.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Sends a heartbeat ping on an interval until the sender is dropped or the
+/// connection fails.
+async fn send_heartbeats(writer: Weak<SharedWriterInner>, heartbeat: SharedHeartbeat) {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let Some(writer) = writer.upgrade() else {
+            return;
+        };
+        let nonce = lock(&heartbeat).ping();
+        let Ok(message) = bincode::serialize(&ToServer::Heartbeat(nonce)) else {
+            return;
+        };
+        if writer.lock().await.send(message.into()).await.is_err() {
+            return;
+        }
+    }
+}
+
 pub struct TrainlappcommsSender {
-    sender: FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>,
+    sender: SharedWriter,
+    location_policy: SharedPolicy,
+    last_location: Option<DetailedLocation>,
+    heartbeat: SharedHeartbeat,
+    upload_token: SharedToken,
+    upload_events: SharedUploadEvents,
+    pictures: Arc<PictureCache>,
 }
 
 impl TrainlappcommsSender {
     pub async fn send(&mut self, message: &ToServer) -> Result<(), Error> {
         match self
             .sender
+            .lock()
+            .await
             .send(
                 bincode::serialize(message)
                     .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
@@ -28,32 +147,243 @@ impl TrainlappcommsSender {
             )
             .await
         {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                if let ToServer::Location(location) = message {
+                    self.last_location = Some(location.clone());
+                }
+                Ok(())
+            }
             Err(e) => Err(Error::other(e)),
         }
     }
+
+    /// Sends a location, unless the current location policy doesn't allow one
+    /// yet. Returns whether the location was sent.
+    pub async fn send_location(&mut self, location: &DetailedLocation) -> Result<bool, Error> {
+        if let (Some(policy), Some(last)) = (self.location_policy(), &self.last_location) {
+            if !policy.allows(last, location) {
+                return Ok(false);
+            }
+        }
+        self.send(&ToServer::Location(location.clone())).await?;
+        Ok(true)
+    }
+
+    /// The location policy the server asked for, if it sent one yet.
+    /// Use this to configure how the os should deliver locations.
+    pub fn location_policy(&self) -> Option<LocationPolicy> {
+        self.location_policy
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// The token needed for uploading pictures, once the server sent one after login.
+    pub fn upload_token(&self) -> Option<String> {
+        self.upload_token
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// How well the connection is doing, judging by the heartbeats.
+    pub fn connection_quality(&self) -> ConnectionQuality {
+        lock(&self.heartbeat).quality()
+    }
+
+    /// The smoothed round trip time of the heartbeats, once one was answered.
+    pub fn rtt(&self) -> Option<Duration> {
+        lock(&self.heartbeat).rtt
+    }
+
+    /// The cache pictures from the server end up in.
+    pub fn picture_cache(&self) -> &Arc<PictureCache> {
+        &self.pictures
+    }
+
+    /// Returns the pictures with the given ids, requesting only those that
+    /// aren't cached. Pictures the server doesn't know are left out, and this
+    /// fails if the server has an internal error or doesn't answer. The
+    /// receiver has to be polled at the same time, since the pictures arrive
+    /// there. They are still returned by `recv` as usual, too.
+    pub async fn get_pictures(&mut self, ids: &[u64]) -> Result<Vec<JuhuiPicture>, Error> {
+        self.get_cached(ids, false).await
+    }
+
+    /// Like `get_pictures`, but for thumbnails, in the size set on the cache.
+    pub async fn get_thumbnails(&mut self, ids: &[u64]) -> Result<Vec<JuhuiPicture>, Error> {
+        self.get_cached(ids, true).await
+    }
+
+    async fn get_cached(
+        &mut self,
+        ids: &[u64],
+        thumbnails: bool,
+    ) -> Result<Vec<JuhuiPicture>, Error> {
+        let mut missing: HashSet<u64> = ids
+            .iter()
+            .copied()
+            .filter(|id| !self.pictures.contains(*id, thumbnails))
+            .collect();
+        if !missing.is_empty() {
+            let mut arrived = self.pictures.subscribe();
+            let request: Vec<u64> = missing.iter().copied().collect();
+            self.send(&match (thumbnails, self.pictures.thumbnail_size()) {
+                (true, Some(size)) => ToServer::RequestThumbnailsSized { ids: request, size },
+                (true, None) => ToServer::RequestThumbnails(request),
+                (false, _) => ToServer::RequestPictures(request),
+            })
+            .await?;
+            // the server answers with everything it found in one message, so
+            // the first one with any of the ids, or an empty one, is the answer
+            loop {
+                let arrival = match tokio::time::timeout(HEARTBEAT_TIMEOUT, arrived.recv()).await {
+                    Err(_) => {
+                        return Err(Error::new(
+                            ErrorKind::TimedOut,
+                            "trainlappcomms didn't send the pictures",
+                        ))
+                    }
+                    Ok(Ok(arrival)) => arrival,
+                    Ok(Err(tokio::sync::broadcast::error::RecvError::Lagged(_))) => {
+                        missing.retain(|id| !self.pictures.contains(*id, thumbnails));
+                        if missing.is_empty() {
+                            break;
+                        }
+                        continue;
+                    }
+                    Ok(Err(tokio::sync::broadcast::error::RecvError::Closed)) => break,
+                };
+                match arrival {
+                    Arrival::Pictures(keys)
+                        if keys.is_empty()
+                            || keys.iter().any(|(id, is_thumbnail)| {
+                                *is_thumbnail == thumbnails && missing.contains(id)
+                            }) =>
+                    {
+                        break
+                    }
+                    Arrival::Pictures(_) => (),
+                    Arrival::Error(ClientError::NotFound(_)) => break,
+                    Arrival::Error(err @ ClientError::InternalError) => {
+                        return Err(Error::other(err.to_string()))
+                    }
+                    // errors aren't tagged with their request, and the rest
+                    // may well belong to other messages, like a `TooRapid`
+                    // for a location, so they just leave it to the timeout
+                    Arrival::Error(_) => (),
+                }
+            }
+        }
+        let pictures = self.pictures.clone();
+        let ids = ids.to_vec();
+        // reading the disk store blocks
+        tokio::task::spawn_blocking(move || {
+            ids.iter()
+                .filter_map(|id| pictures.get(*id, thumbnails))
+                .collect()
+        })
+        .await
+        .map_err(Error::other)
+    }
 }
 
 pub struct TrainlappcommsReceiver {
     receiver: FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
+    location_policy: SharedPolicy,
+    heartbeat: SharedHeartbeat,
+    upload_token: SharedToken,
+    upload_events: SharedUploadEvents,
+    pictures: Arc<PictureCache>,
 }
 
 impl TrainlappcommsReceiver {
+    /// Receives the next message from the server. Answers to heartbeats and, while
+    /// a `PictureUpload` is being sent, to uploads are handled here and not
+    /// returned. Fails with `ErrorKind::TimedOut` if the server stops answering
+    /// heartbeats.
     pub async fn recv(&mut self) -> Result<ToApp, Error> {
-        bincode::deserialize(&self.receiver.next().await.ok_or(Error::new(
-            ErrorKind::ConnectionAborted,
-            "connection with trainlappcomms ended",
-        ))??)
-        .map_err(|e| {
-            Error::new(
-                ErrorKind::InvalidData,
-                format!("message from trainappcomms couldn't be decoded: {}", e),
-            )
-        })
+        loop {
+            let frame = tokio::time::timeout(HEARTBEAT_TIMEOUT, self.receiver.next())
+                .await
+                .map_err(|_| {
+                    Error::new(
+                        ErrorKind::TimedOut,
+                        "trainlappcomms stopped answering heartbeats",
+                    )
+                })?
+                .ok_or(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "connection with trainlappcomms ended",
+                ))??;
+            let message = bincode::deserialize(&frame).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("message from trainappcomms couldn't be decoded: {}", e),
+                )
+            })?;
+            match &message {
+                ToApp::SetLocationPolicy(policy) => {
+                    *self
+                        .location_policy
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner()) = Some(policy.clone());
+                }
+                ToApp::UploadStarted { .. }
+                | ToApp::UploadChunkAck { .. }
+                | ToApp::UploadFinished { .. } => {
+                    if let Some(events) = self
+                        .upload_events
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .as_ref()
+                    {
+                        if events.send(message.clone()).is_ok() {
+                            continue;
+                        }
+                    }
+                }
+                ToApp::Pictures(pictures) => {
+                    let cache = self.pictures.clone();
+                    let pictures = pictures.clone();
+                    // writing to the disk store blocks
+                    tokio::task::spawn_blocking(move || cache.insert(&pictures));
+                }
+                ToApp::Error(err) => self.pictures.failed(err.clone()),
+                ToApp::UploadToken(token) => {
+                    *self.upload_token.lock().unwrap_or_else(|e| e.into_inner()) =
+                        Some(token.clone());
+                }
+                ToApp::Pong(nonce) => {
+                    lock(&self.heartbeat).pong(*nonce);
+                    continue;
+                }
+                _ => (),
+            }
+            return Ok(message);
+        }
+    }
+
+    /// How well the connection is doing, judging by the heartbeats.
+    pub fn connection_quality(&self) -> ConnectionQuality {
+        lock(&self.heartbeat).quality()
+    }
+
+    /// The smoothed round trip time of the heartbeats, once one was answered.
+    pub fn rtt(&self) -> Option<Duration> {
+        lock(&self.heartbeat).rtt
     }
 }
 
 pub async fn connect() -> Result<(TrainlappcommsReceiver, TrainlappcommsSender), Error> {
+    connect_with_cache(Arc::default()).await
+}
+
+/// Like `connect`, but keeps pictures in `pictures`. Pass the same cache when
+/// reconnecting, so that it isn't empty again after every dropped connection.
+pub async fn connect_with_cache(
+    pictures: Arc<PictureCache>,
+) -> Result<(TrainlappcommsReceiver, TrainlappcommsSender), Error> {
     let (rx, tx) = TcpStream::connect(
         if cfg!(debug_assertions) || option_env!("TL_DEBUG").is_some() {
             "trainlag.ch:42314"
@@ -63,30 +393,262 @@ pub async fn connect() -> Result<(TrainlappcommsReceiver, TrainlappcommsSender),
     )
     .await?
     .into_split();
+    let location_policy = SharedPolicy::default();
+    let heartbeat = SharedHeartbeat::default();
+    let upload_token = SharedToken::default();
+    let upload_events = SharedUploadEvents::default();
+    let sender = Arc::new(tokio::sync::Mutex::new(FramedWrite::new(
+        tx,
+        LengthDelimitedCodec::new(),
+    )));
+    tokio::spawn(send_heartbeats(Arc::downgrade(&sender), heartbeat.clone()));
     Ok((
         TrainlappcommsReceiver {
             receiver: FramedRead::new(rx, LengthDelimitedCodec::new()),
+            location_policy: location_policy.clone(),
+            heartbeat: heartbeat.clone(),
+            upload_token: upload_token.clone(),
+            upload_events: upload_events.clone(),
+            pictures: pictures.clone(),
         },
         TrainlappcommsSender {
-            sender: FramedWrite::new(tx, LengthDelimitedCodec::new()),
+            sender,
+            location_policy,
+            last_location: None,
+            heartbeat,
+            upload_token,
+            upload_events,
+            pictures,
         },
     ))
 }
 
+/// Collects locations while there is no connection (e.g. in a tunnel) and
+/// uploads them in one go once there is one again, so that the past locations
+/// the server has don't have gaps.
+pub struct LocationBuffer {
+    locations: VecDeque<DetailedLocation>,
+    capacity: usize,
+}
+
+impl LocationBuffer {
+    /// Locations sent per `ToServer::LocationBatch`, to keep frames reasonably small.
+    const BATCH_SIZE: usize = 500;
+
+    /// Creates a buffer holding at most `capacity` locations. Once it is full,
+    /// the oldest locations are dropped.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            locations: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, location: DetailedLocation) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.locations.len() == self.capacity {
+            self.locations.pop_front();
+        }
+        self.locations.push_back(location);
+    }
+
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+
+    /// Sends a location, uploading buffered locations first. If sending fails,
+    /// the location is buffered and the error is returned. Returns `Ok(false)`
+    /// if the location policy skipped the location.
+    pub async fn send_location(
+        &mut self,
+        sender: &mut TrainlappcommsSender,
+        location: DetailedLocation,
+    ) -> Result<bool, Error> {
+        if let Err(err) = self.flush(sender).await {
+            self.push(location);
+            return Err(err);
+        }
+        match sender.send_location(&location).await {
+            Ok(sent) => Ok(sent),
+            Err(err) => {
+                self.push(location);
+                Err(err)
+            }
+        }
+    }
+
+    /// Uploads all buffered locations. Call this after reconnecting.
+    /// Locations are only removed from the buffer once they were sent.
+    pub async fn flush(&mut self, sender: &mut TrainlappcommsSender) -> Result<(), Error> {
+        while !self.locations.is_empty() {
+            let count = self.locations.len().min(Self::BATCH_SIZE);
+            let batch = self.locations.range(..count).cloned().collect();
+            sender.send(&ToServer::LocationBatch(batch)).await?;
+            self.locations.drain(..count);
+        }
+        Ok(())
+    }
+}
+
+/// A picture uploaded in chunks over the main connection. If the connection
+/// drops, keep this around and call `send` again after reconnecting, the
+/// upload then continues where it stopped.
+pub struct PictureUpload {
+    target: UploadTarget,
+    picture: Vec<u8>,
+    id: Option<u64>,
+}
+
+impl PictureUpload {
+    /// Bytes sent per `ToServer::UploadChunk`.
+    const CHUNK_SIZE: usize = 128 * 1024;
+
+    pub fn new(target: UploadTarget, picture: Vec<u8>) -> Self {
+        Self {
+            target,
+            picture,
+            id: None,
+        }
+    }
+
+    /// The id the server assigned to the upload, once it did.
+    pub fn id(&self) -> Option<u64> {
+        self.id
+    }
+
+    /// Uploads the picture, calling `progress` with the number of bytes the
+    /// server has and the total after every chunk. The receiver has to be
+    /// polled at the same time, since the server's answers arrive there.
+    pub async fn send(
+        &mut self,
+        sender: &mut TrainlappcommsSender,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<PictureReply, Error> {
+        let (events_tx, mut events) = tokio::sync::mpsc::unbounded_channel();
+        *sender
+            .upload_events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(events_tx);
+        let result = self.run(sender, &mut events, &mut progress).await;
+        *sender
+            .upload_events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = None;
+        result
+    }
+
+    async fn run(
+        &mut self,
+        sender: &mut TrainlappcommsSender,
+        events: &mut tokio::sync::mpsc::UnboundedReceiver<ToApp>,
+        progress: &mut impl FnMut(u64, u64),
+    ) -> Result<PictureReply, Error> {
+        let total = self.picture.len() as u64;
+        sender
+            .send(&ToServer::BeginUpload {
+                resume: self.id,
+                target: self.target,
+                size: total,
+            })
+            .await?;
+        let (upload_id, mut received) = match next_upload_event(events).await? {
+            ToApp::UploadStarted {
+                upload_id,
+                received,
+            } => (upload_id, received),
+            ToApp::UploadFinished { reply, .. } => {
+                self.id = None;
+                return Ok(reply);
+            }
+            _ => return Err(unexpected_upload_event()),
+        };
+        self.id = Some(upload_id);
+        progress(received, total);
+        while received < total {
+            let start = received as usize;
+            let end = (start + Self::CHUNK_SIZE).min(self.picture.len());
+            sender
+                .send(&ToServer::UploadChunk {
+                    upload_id,
+                    offset: received,
+                    data: self.picture[start..end].to_vec(),
+                })
+                .await?;
+            received = match next_upload_event(events).await? {
+                ToApp::UploadChunkAck { received, .. } => received,
+                ToApp::UploadFinished { reply, .. } => {
+                    self.id = None;
+                    return Ok(reply);
+                }
+                _ => return Err(unexpected_upload_event()),
+            };
+            progress(received, total);
+        }
+        sender.send(&ToServer::FinishUpload { upload_id }).await?;
+        match next_upload_event(events).await? {
+            ToApp::UploadFinished { reply, .. } => {
+                self.id = None;
+                Ok(reply)
+            }
+            _ => Err(unexpected_upload_event()),
+        }
+    }
+}
+
+async fn next_upload_event(
+    events: &mut tokio::sync::mpsc::UnboundedReceiver<ToApp>,
+) -> Result<ToApp, Error> {
+    tokio::time::timeout(HEARTBEAT_TIMEOUT, events.recv())
+        .await
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::TimedOut,
+                "trainlappcomms didn't answer the upload",
+            )
+        })?
+        .ok_or(Error::new(
+            ErrorKind::ConnectionAborted,
+            "connection with trainlappcomms ended",
+        ))
+}
+
+fn unexpected_upload_event() -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        "trainlappcomms answered the upload with something unexpected",
+    )
+}
+
+/// Uploads a team's profile picture. `token` comes from
+/// `TrainlappcommsSender::upload_token`. Pictures straight from the camera
+/// should go through `preprocess::preprocess` first, for this and the other uploads.
 pub async fn send_team_picture(
+    token: String,
     picture: Vec<u8>,
     session: u64,
     team: usize,
-) -> Result<(), std::io::Error> {
+) -> Result<PictureReply, std::io::Error> {
     let wrapper = PictureWrapper {
+        token,
         kind: PictureKind::TeamProfile { session, team },
         picture,
     };
     send_picture(wrapper).await
 }
 
-pub async fn send_player_picture(picture: Vec<u8>, player: u64) -> Result<(), std::io::Error> {
+pub async fn send_player_picture(
+    token: String,
+    picture: Vec<u8>,
+    player: u64,
+) -> Result<PictureReply, std::io::Error> {
     let wrapper = PictureWrapper {
+        token,
         kind: PictureKind::PlayerProfile(player),
         picture,
     };
@@ -94,12 +656,14 @@ pub async fn send_player_picture(picture: Vec<u8>, player: u64) -> Result<(), st
 }
 
 pub async fn send_period_picture(
+    token: String,
     picture: Vec<u8>,
     session: u64,
     team: usize,
     period_id: usize,
-) -> Result<(), std::io::Error> {
+) -> Result<PictureReply, std::io::Error> {
     let wrapper = PictureWrapper {
+        token,
         kind: PictureKind::Period {
             session,
             team,
@@ -110,7 +674,8 @@ pub async fn send_period_picture(
     send_picture(wrapper).await
 }
 
-async fn send_picture(pic: PictureWrapper) -> Result<(), std::io::Error> {
+/// Uploads a picture and waits for the server to tell whether it worked.
+async fn send_picture(pic: PictureWrapper) -> Result<PictureReply, std::io::Error> {
     let message = bincode::serialize(&pic).unwrap();
     let mut connection = TcpStream::connect(if cfg!(debug_assertions) {
         "trainlag.ch:42315"
@@ -119,6 +684,14 @@ async fn send_picture(pic: PictureWrapper) -> Result<(), std::io::Error> {
     })
     .await?;
     connection.write_all(&message).await?;
+    // only shuts down our side, the reply comes back over the other one
     connection.shutdown().await?;
-    Ok(())
+    let mut reply = Vec::new();
+    connection.read_to_end(&mut reply).await?;
+    bincode::deserialize(&reply).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("reply to picture upload couldn't be decoded: {}", e),
+        )
+    })
 }
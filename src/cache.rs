@@ -0,0 +1,429 @@
+//! Keeping pictures around on the phone, so that screens showing the same
+//! pictures don't download them again every time. Pictures never change once
+//! uploaded, so nothing in here ever goes stale.
+
+use super::{ClientError, JuhuiPicture, ThumbnailSize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use tokio::sync::broadcast;
+
+/// A picture id and whether it's the thumbnail.
+pub type PictureKey = (u64, bool);
+
+/// What the server sent while pictures were requested.
+#[derive(Clone, Debug)]
+pub(crate) enum Arrival {
+    /// Pictures were stored, possibly none if the server had none of them.
+    Pictures(Vec<PictureKey>),
+    Error(ClientError),
+}
+
+/// Pictures by id and whether they are thumbnails, in memory and optionally on
+/// disk. Both stores drop the least recently used pictures once they are full.
+/// Share it between connections with an `Arc`, see `api::connect_with_cache`.
+///
+/// All thumbnails in a cache are expected to have the same size, the one set
+/// with `with_thumbnail_size`. Requesting other sizes on a connection using the
+/// cache mixes them up.
+///
+/// With a disk store, `get` and `insert` read and write files, so in async code
+/// they should be called through `tokio::task::spawn_blocking`.
+pub struct PictureCache {
+    memory: Mutex<Lru>,
+    disk: Option<Mutex<DiskStore>>,
+    thumbnail_size: Option<ThumbnailSize>,
+    /// What arrived from the server, for `get_pictures` to wait on.
+    arrived: broadcast::Sender<Arrival>,
+}
+
+impl Default for PictureCache {
+    /// 32 MiB in memory and nothing on disk.
+    fn default() -> Self {
+        Self::new(32 << 20)
+    }
+}
+
+impl PictureCache {
+    /// A cache keeping up to `memory_bytes` of pictures in memory.
+    pub fn new(memory_bytes: u64) -> Self {
+        Self {
+            memory: Mutex::new(Lru::new(memory_bytes)),
+            disk: None,
+            thumbnail_size: None,
+            arrived: broadcast::channel(16).0,
+        }
+    }
+
+    /// Also keeps up to `max_bytes` of pictures in `dir`, which survive restarts
+    /// of the app. Pictures already in `dir` are picked up again.
+    pub fn with_disk(mut self, dir: impl Into<PathBuf>, max_bytes: u64) -> std::io::Result<Self> {
+        self.disk = Some(Mutex::new(DiskStore::open(dir.into(), max_bytes)?));
+        Ok(self)
+    }
+
+    /// The size `api::TrainlappcommsSender::get_thumbnails` requests thumbnails
+    /// in, instead of truinlag's usual one. A disk store from a run with
+    /// another size should be cleared.
+    pub fn with_thumbnail_size(mut self, size: ThumbnailSize) -> Self {
+        self.thumbnail_size = Some(size);
+        self
+    }
+
+    pub fn thumbnail_size(&self) -> Option<ThumbnailSize> {
+        self.thumbnail_size
+    }
+
+    /// Looks for a picture in memory, then on disk.
+    pub fn get(&self, id: u64, is_thumbnail: bool) -> Option<JuhuiPicture> {
+        let key = (id, is_thumbnail);
+        if let Some(picture) = lock(&self.memory).get(key) {
+            return Some(picture);
+        }
+        let picture = lock(self.disk.as_ref()?).get(key)?;
+        lock(&self.memory).insert(picture.clone());
+        Some(picture)
+    }
+
+    pub fn contains(&self, id: u64, is_thumbnail: bool) -> bool {
+        let key = (id, is_thumbnail);
+        lock(&self.memory).contains(key)
+            || self
+                .disk
+                .as_ref()
+                .is_some_and(|disk| lock(disk).contains(key))
+    }
+
+    /// Adds pictures, usually ones that just arrived from the server.
+    pub fn insert(&self, pictures: &[JuhuiPicture]) {
+        {
+            let mut memory = lock(&self.memory);
+            for picture in pictures {
+                memory.insert(picture.clone());
+            }
+        }
+        if let Some(disk) = &self.disk {
+            let mut disk = lock(disk);
+            for picture in pictures {
+                disk.insert(picture);
+            }
+        }
+        // nobody listening is fine
+        let _ = self.arrived.send(Arrival::Pictures(
+            pictures.iter().map(|p| (p.id, p.is_thumbnail)).collect(),
+        ));
+    }
+
+    /// Wakes up `get_pictures` calls waiting for an answer when the server
+    /// sent an error instead.
+    pub(crate) fn failed(&self, err: ClientError) {
+        let _ = self.arrived.send(Arrival::Error(err));
+    }
+
+    /// Removes everything, including what is on disk.
+    pub fn clear(&self) {
+        lock(&self.memory).clear();
+        if let Some(disk) = &self.disk {
+            lock(disk).clear();
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Arrival> {
+        self.arrived.subscribe()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Least recently used eviction by total size. Caches on phones hold at most a
+/// few hundred pictures, so finding the oldest one by scanning is fine.
+struct Lru {
+    pictures: HashMap<PictureKey, (JuhuiPicture, u64)>,
+    bytes: u64,
+    max_bytes: u64,
+    clock: u64,
+}
+
+impl Lru {
+    fn new(max_bytes: u64) -> Self {
+        Self {
+            pictures: HashMap::new(),
+            bytes: 0,
+            max_bytes,
+            clock: 0,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn contains(&self, key: PictureKey) -> bool {
+        self.pictures.contains_key(&key)
+    }
+
+    fn get(&mut self, key: PictureKey) -> Option<JuhuiPicture> {
+        let now = self.tick();
+        let (picture, used) = self.pictures.get_mut(&key)?;
+        *used = now;
+        Some(picture.clone())
+    }
+
+    fn insert(&mut self, picture: JuhuiPicture) {
+        let size = picture.data.len() as u64;
+        if size > self.max_bytes {
+            return;
+        }
+        let now = self.tick();
+        if let Some((old, _)) = self
+            .pictures
+            .insert((picture.id, picture.is_thumbnail), (picture, now))
+        {
+            self.bytes -= old.data.len() as u64;
+        }
+        self.bytes += size;
+        while self.bytes > self.max_bytes {
+            let Some(oldest) = oldest(self.pictures.iter().map(|(k, (_, used))| (*k, *used)))
+            else {
+                break;
+            };
+            if let Some((evicted, _)) = self.pictures.remove(&oldest) {
+                self.bytes -= evicted.data.len() as u64;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.pictures.clear();
+        self.bytes = 0;
+    }
+}
+
+/// One file per picture, with only the sizes and last uses kept in memory.
+struct DiskStore {
+    dir: PathBuf,
+    files: HashMap<PictureKey, (u64, u64)>,
+    bytes: u64,
+    max_bytes: u64,
+    clock: u64,
+}
+
+impl DiskStore {
+    fn open(dir: PathBuf, max_bytes: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let Some(key) = entry.file_name().to_str().and_then(parse_file_name) else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            found.push((metadata.modified().ok(), key, metadata.len()));
+        }
+        // files from earlier runs count as used in the order they were written
+        found.sort_by_key(|(modified, _, _)| *modified);
+        let mut store = Self {
+            dir,
+            files: HashMap::new(),
+            bytes: 0,
+            max_bytes,
+            clock: 0,
+        };
+        for (_, key, size) in found {
+            let now = store.tick();
+            store.files.insert(key, (size, now));
+            store.bytes += size;
+        }
+        store.evict();
+        Ok(store)
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn path(&self, (id, is_thumbnail): PictureKey) -> PathBuf {
+        self.dir.join(format!(
+            "{}.{}",
+            id,
+            if is_thumbnail { "thumb" } else { "pic" }
+        ))
+    }
+
+    fn contains(&self, key: PictureKey) -> bool {
+        self.files.contains_key(&key)
+    }
+
+    fn get(&mut self, key: PictureKey) -> Option<JuhuiPicture> {
+        self.files.get(&key)?;
+        let picture = std::fs::read(self.path(key))
+            .ok()
+            .and_then(|bytes| bincode::deserialize::<JuhuiPicture>(&bytes).ok());
+        match picture {
+            Some(picture) => {
+                let now = self.tick();
+                if let Some((_, used)) = self.files.get_mut(&key) {
+                    *used = now;
+                }
+                Some(picture)
+            }
+            None => {
+                // written by an older version or damaged, either way useless
+                self.remove(key);
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, picture: &JuhuiPicture) {
+        let key = (picture.id, picture.is_thumbnail);
+        let Ok(bytes) = bincode::serialize(picture) else {
+            return;
+        };
+        let size = bytes.len() as u64;
+        if size > self.max_bytes || std::fs::write(self.path(key), &bytes).is_err() {
+            return;
+        }
+        let now = self.tick();
+        if let Some((old, _)) = self.files.insert(key, (size, now)) {
+            self.bytes -= old;
+        }
+        self.bytes += size;
+        self.evict();
+    }
+
+    fn remove(&mut self, key: PictureKey) {
+        if let Some((size, _)) = self.files.remove(&key) {
+            self.bytes -= size;
+            let _ = std::fs::remove_file(self.path(key));
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.bytes > self.max_bytes {
+            let Some(oldest) = oldest(self.files.iter().map(|(k, (_, used))| (*k, *used))) else {
+                break;
+            };
+            self.remove(oldest);
+        }
+    }
+
+    fn clear(&mut self) {
+        let keys: Vec<_> = self.files.keys().copied().collect();
+        for key in keys {
+            self.remove(key);
+        }
+    }
+}
+
+fn oldest(entries: impl Iterator<Item = (PictureKey, u64)>) -> Option<PictureKey> {
+    entries.min_by_key(|(_, used)| *used).map(|(key, _)| key)
+}
+
+fn parse_file_name(name: &str) -> Option<PictureKey> {
+    let (id, extension) = name.split_once('.')?;
+    let is_thumbnail = match extension {
+        "thumb" => true,
+        "pic" => false,
+        _ => return None,
+    };
+    Some((id.parse().ok()?, is_thumbnail))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn picture(id: u64, is_thumbnail: bool, size: usize) -> JuhuiPicture {
+        JuhuiPicture {
+            data: vec![0; size],
+            is_thumbnail,
+            id,
+            proof: None,
+            uploader: None,
+            team: None,
+            period: None,
+            created_at: None,
+            width: None,
+            height: None,
+            content_type: None,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "trainlappcomms_cache_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = PictureCache::new(30);
+        cache.insert(&[picture(1, false, 10), picture(2, false, 10)]);
+        assert!(cache.get(1, false).is_some());
+        cache.insert(&[picture(3, false, 15)]);
+        assert!(cache.contains(1, false));
+        assert!(!cache.contains(2, false));
+        assert!(cache.contains(3, false));
+    }
+
+    #[test]
+    fn keeps_thumbnails_apart() {
+        let cache = PictureCache::new(100);
+        cache.insert(&[picture(1, true, 5)]);
+        assert!(cache.contains(1, true));
+        assert!(!cache.contains(1, false));
+    }
+
+    #[test]
+    fn skips_pictures_larger_than_the_cache() {
+        let cache = PictureCache::new(10);
+        cache.insert(&[picture(1, false, 5), picture(2, false, 20)]);
+        assert!(cache.contains(1, false));
+        assert!(!cache.contains(2, false));
+    }
+
+    #[test]
+    fn disk_store_survives_restarts() {
+        let dir = temp_dir("restart");
+        let cache = PictureCache::new(0).with_disk(&dir, 1 << 20).unwrap();
+        cache.insert(&[picture(1, false, 100), picture(1, true, 10)]);
+        drop(cache);
+        let cache = PictureCache::new(0).with_disk(&dir, 1 << 20).unwrap();
+        assert_eq!(cache.get(1, false).unwrap().data.len(), 100);
+        assert_eq!(cache.get(1, true).unwrap().data.len(), 10);
+        cache.clear();
+        assert!(!cache.contains(1, false));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn disk_store_keeps_to_its_size() {
+        let dir = temp_dir("size");
+        let cache = PictureCache::new(0).with_disk(&dir, 300).unwrap();
+        for id in 0..10 {
+            cache.insert(&[picture(id, false, 100)]);
+        }
+        let stored = std::fs::read_dir(&dir).unwrap().count();
+        assert!(stored < 3, "{} pictures stored", stored);
+        assert!(cache.contains(9, false));
+        assert!(!cache.contains(0, false));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn announces_empty_answers() {
+        let cache = PictureCache::default();
+        let mut arrived = cache.subscribe();
+        cache.insert(&[]);
+        assert!(matches!(arrived.try_recv(), Ok(Arrival::Pictures(keys)) if keys.is_empty()));
+    }
+}
@@ -1,4 +1,5 @@
 use std::num::NonZeroU32;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 
@@ -6,6 +7,9 @@ use serde::{Deserialize, Serialize};
 use chrono::Timelike;
 
 pub mod api;
+pub mod cache;
+#[cfg(feature = "preprocess")]
+pub mod preprocess;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ToServer {
@@ -33,6 +37,59 @@ pub enum ToServer {
         of_past_seconds: Option<NonZeroU32>,
         team_id: usize,
     },
+    /// Locations that were collected while there was no connection,
+    /// with their original timestamps.
+    LocationBatch(Vec<DetailedLocation>),
+    /// Only receive the locations of the given teams (in addition to earlier
+    /// subscriptions), with at least `max_rate` between two locations of a team.
+    /// Until the first subscription, the locations of all teams are received.
+    /// Unsubscribing from every subscribed team doesn't change that back, that
+    /// takes a `SubscribeAllLocations`.
+    SubscribeLocations {
+        teams: Vec<usize>,
+        max_rate: Option<Duration>,
+    },
+    /// Stops receiving the locations of the given teams. Works both with
+    /// subscriptions and while receiving the locations of all teams.
+    UnsubscribeLocations {
+        teams: Vec<usize>,
+    },
+    /// Whether locations of several teams may be sent together as `ToApp::Locations`.
+    SetLocationBatching(bool),
+    /// Confirms that the player has seen the announcement with the given id.
+    AckAnnouncement(u64),
+    /// Starts uploading a picture in chunks, answered with `ToApp::UploadStarted`.
+    /// With `resume` set to the id of an earlier upload, that upload is continued
+    /// if the server still has it, e.g. after the connection dropped.
+    BeginUpload {
+        resume: Option<u64>,
+        target: UploadTarget,
+        size: u64,
+    },
+    /// A part of a picture, answered with `ToApp::UploadChunkAck`. Chunks have to
+    /// be sent in order, `offset` has to be what the server last said it received.
+    UploadChunk {
+        upload_id: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Ends an upload once all chunks were acknowledged, answered with
+    /// `ToApp::UploadFinished`.
+    FinishUpload {
+        upload_id: u64,
+    },
+    /// Goes back to receiving the locations of all teams, forgetting earlier
+    /// subscriptions and unsubscriptions.
+    SubscribeAllLocations,
+    /// Answered by trainlappcomms itself with `ToApp::Pong` carrying the same
+    /// number. The library sends these on its own to keep track of the connection.
+    Heartbeat(u64),
+    /// Like `RequestThumbnails`, but the server scales the pictures down to
+    /// fit `size`, for at most 64 ids at once.
+    RequestThumbnailsSized {
+        ids: Vec<u64>,
+        size: ThumbnailSize,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -49,6 +106,16 @@ pub struct Everything {
     pub you: u64,
     pub your_team: usize,
     pub your_session: u64,
+    /// whether the players of all teams are currently connected
+    pub presence: Vec<PlayerPresence>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlayerPresence {
+    pub player: u64,
+    pub online: bool,
+    /// when the player was last connected, if ever
+    pub last_seen: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -75,6 +142,106 @@ pub enum ToApp {
     GameStarted(Everything),
     EventOccurred(Event, Everything),
     YouLeftGracePeriod(Everything),
+    /// How often the app should send its location. Sent after login and whenever
+    /// the role of the team changes.
+    SetLocationPolicy(LocationPolicy),
+    /// The newest locations of several teams at once, only sent to apps that
+    /// enabled batching with `ToServer::SetLocationBatching`.
+    Locations(Vec<(usize, DetailedLocation)>),
+    /// A player connected or disconnected.
+    Presence {
+        player: u64,
+        online: bool,
+        last_seen: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    /// A message from the game masters, as opposed to `Ping`, which is just a keepalive.
+    /// If `requires_ack` is set, the app should answer with `ToServer::AckAnnouncement`
+    /// once the player has seen it.
+    Announcement {
+        id: u64,
+        title: String,
+        body: String,
+        severity: Severity,
+        sent_at: chrono::DateTime<chrono::Utc>,
+        target: AnnouncementTarget,
+        requires_ack: bool,
+    },
+    /// Sent after a successful login. Uploads on the picture port need to carry
+    /// this in `PictureWrapper::token`, it stays valid while the app is connected.
+    UploadToken(String),
+    /// An upload was started or resumed. `received` bytes are already there,
+    /// the next chunk has to start at that offset.
+    UploadStarted {
+        upload_id: u64,
+        received: u64,
+    },
+    UploadChunkAck {
+        upload_id: u64,
+        received: u64,
+    },
+    /// An upload is over, either because it was finished or because it failed.
+    UploadFinished {
+        upload_id: u64,
+        reply: PictureReply,
+    },
+    /// The answer to `ToServer::AttachPeriodPictures`. `accepted` has the ids of
+    /// the pictures that were attached, in the order they were sent, `rejected`
+    /// the index of every other picture along with why it was rejected. If the
+    /// server is too busy to look at the pictures, `ToApp::Error` is sent instead.
+    PicturesAttached {
+        event_id: usize,
+        accepted: Vec<u64>,
+        rejected: Vec<(usize, ClientError)>,
+    },
+    /// The answer to `ToServer::Heartbeat`.
+    Pong(u64),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+/// Who an announcement is meant for.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnouncementTarget {
+    Session,
+    Team(usize),
+    Player(u64),
+}
+
+impl ToApp {
+    /// The state contained in the message, if there is one.
+    pub fn everything(&self) -> Option<&Everything> {
+        match self {
+            Self::Everything(everything)
+            | Self::BecomeCatcher(everything)
+            | Self::BecomeRunner(everything)
+            | Self::ChallengeCompleted(_, everything)
+            | Self::BecomeNoGameRunning(everything)
+            | Self::GameStarted(everything)
+            | Self::EventOccurred(_, everything)
+            | Self::YouLeftGracePeriod(everything) => Some(everything),
+            _ => None,
+        }
+    }
+
+    /// Mutable access to the state contained in the message, if there is one.
+    pub fn everything_mut(&mut self) -> Option<&mut Everything> {
+        match self {
+            Self::Everything(everything)
+            | Self::BecomeCatcher(everything)
+            | Self::BecomeRunner(everything)
+            | Self::ChallengeCompleted(_, everything)
+            | Self::BecomeNoGameRunning(everything)
+            | Self::GameStarted(everything)
+            | Self::EventOccurred(_, everything)
+            | Self::YouLeftGracePeriod(everything) => Some(everything),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -95,6 +262,8 @@ pub enum ClientError {
     PictureProblem,    // An Image-related error
     TooRapid,          // When requests are sent too rapidly
     TooFewChallenges,  // When there are too few challenges to start a game
+    // An uploaded picture was turned away, with the reason
+    PictureRejected(String),
 }
 
 impl std::fmt::Display for ClientError {
@@ -119,6 +288,7 @@ impl std::fmt::Display for ClientError {
                 f,
                 "there are not enough challenges to start a game in the challenge db"
             ),
+            Self::PictureRejected(reason) => write!(f, "the picture was rejected: {}", reason),
         }
     }
 }
@@ -217,20 +387,96 @@ pub struct JuhuiPicture {
     pub data: Vec<u8>,
     pub is_thumbnail: bool,
     pub id: u64,
+    /// Only for period pictures whose metadata said when or where they were taken.
+    pub proof: Option<PictureProof>,
+    /// The player who uploaded the picture. The server only remembers this and
+    /// the other details about uploads for the last 10000 pictures, so they are
+    /// missing for older ones.
+    pub uploader: Option<u64>,
+    /// The team of a team profile or period picture.
+    pub team: Option<usize>,
+    /// The period or event a period picture was attached to.
+    pub period: Option<usize>,
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Like `image/jpeg`, if the format was recognised.
+    pub content_type: Option<String>,
 }
 
 #[cfg(feature = "build-binary")]
-impl From<truinlag::Picture> for JuhuiPicture {
-    fn from(value: truinlag::Picture) -> Self {
+impl JuhuiPicture {
+    /// A picture without any details about its upload, with the dimensions and
+    /// content type read from the header of `data`.
+    pub fn new(id: u64, is_thumbnail: bool, data: Vec<u8>) -> Self {
+        let reader = image::ImageReader::new(std::io::Cursor::new(&data))
+            .with_guessed_format()
+            .ok();
+        let content_type = reader
+            .as_ref()
+            .and_then(|r| r.format())
+            .map(|f| f.to_mime_type().to_string());
+        let (width, height) = reader
+            .and_then(|r| r.into_dimensions().ok())
+            .map_or((None, None), |(w, h)| (Some(w), Some(h)));
         JuhuiPicture {
-            data: value.data.get_bytes(),
-            is_thumbnail: value.is_thumbnail,
-            id: value.id,
+            data,
+            is_thumbnail,
+            id,
+            proof: None,
+            uploader: None,
+            team: None,
+            period: None,
+            created_at: None,
+            width,
+            height,
+            content_type,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[cfg(feature = "build-binary")]
+impl From<truinlag::Picture> for JuhuiPicture {
+    fn from(value: truinlag::Picture) -> Self {
+        JuhuiPicture::new(value.id, value.is_thumbnail, value.data.get_bytes())
+    }
+}
+
+/// The box requested thumbnails are scaled down to fit into, keeping their
+/// aspect ratio. Pictures that fit already are only encoded again.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ThumbnailSize {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub format: ThumbnailFormat,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ThumbnailFormat {
+    Jpeg,
+    Png,
+    /// Lossless, so only smaller than JPEG for drawings and the like.
+    WebP,
+}
+
+/// What the EXIF data of a period picture said about when and where it was
+/// taken. The metadata itself is stripped from the picture, this is all that's
+/// kept, as evidence for the challenge.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PictureProof {
+    /// The camera's local time, EXIF doesn't reliably say which time zone that is.
+    pub taken_at: Option<chrono::NaiveDateTime>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+impl PictureProof {
+    pub fn is_empty(&self) -> bool {
+        self.taken_at.is_none() && self.latitude.is_none() && self.longitude.is_none()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum State {
     GameNotRunning,
     Runner,
@@ -247,6 +493,7 @@ pub struct DetailedLocation {
     pub heading: f32,
     /// speed in m/s
     pub speed: f32,
+    /// unix timestamp in milliseconds
     pub timestamp: i64,
 }
 
@@ -278,6 +525,45 @@ impl From<truinlag::DetailedLocation> for DetailedLocation {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocationAccuracy {
+    Low,
+    Balanced,
+    High,
+}
+
+/// Rules for how often locations should be sent. A location is only sent once
+/// `min_interval` has passed and the app has moved by `min_distance` metres
+/// since the last location that was sent, or once `max_interval` has passed,
+/// so the server still hears from apps that stand still.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LocationPolicy {
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+    /// distance in metres
+    pub min_distance: f32,
+    /// the accuracy the app should request from the os
+    pub accuracy: LocationAccuracy,
+}
+
+impl LocationPolicy {
+    /// Whether `location` should be sent, given that `last` was the last location sent.
+    pub fn allows(&self, last: &DetailedLocation, location: &DetailedLocation) -> bool {
+        use geo::{HaversineDistance, Point};
+        let elapsed = location.timestamp.saturating_sub(last.timestamp);
+        if elapsed < self.min_interval.as_millis() as i64 {
+            return false;
+        }
+        if elapsed >= self.max_interval.as_millis() as i64 {
+            return true;
+        }
+        let distance = Point::new(last.longitude as f64, last.latitude as f64).haversine_distance(
+            &Point::new(location.longitude as f64, location.latitude as f64),
+        );
+        distance >= self.min_distance as f64
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MinimalLocation {
     pub latitude: f32,
@@ -410,10 +696,31 @@ impl From<truinlag::Player> for Player {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PictureWrapper {
+    /// The token from `ToApp::UploadToken`, the player it belongs to has to be
+    /// allowed to change whatever `kind` refers to.
+    pub token: String,
     pub kind: PictureKind,
     pub picture: Vec<u8>,
 }
 
+/// What a picture uploaded with `ToServer::BeginUpload` is for. Unlike on the
+/// picture port, the team and player are those of the logged in app.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UploadTarget {
+    PlayerProfile,
+    TeamProfile,
+    Period { event_id: usize },
+}
+
+/// The server's answer to a picture upload.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum PictureReply {
+    /// The ids the pictures were stored under. Empty for profile pictures,
+    /// which truinlag doesn't hand out ids for.
+    Uploaded(Vec<u64>),
+    Rejected(ClientError),
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum PictureKind {
     TeamProfile {
@@ -427,3 +734,47 @@ pub enum PictureKind {
         period_id: usize,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> LocationPolicy {
+        LocationPolicy {
+            min_interval: Duration::from_secs(10),
+            max_interval: Duration::from_secs(60),
+            min_distance: 25.0,
+            accuracy: LocationAccuracy::Balanced,
+        }
+    }
+
+    /// A location `north` metres north of the first one, `secs` seconds later.
+    fn location(north: f32, secs: i64) -> DetailedLocation {
+        DetailedLocation {
+            latitude: 47.0 + north / 111_195.0,
+            longitude: 8.0,
+            accuracy: 5,
+            heading: 0.0,
+            speed: 0.0,
+            timestamp: secs * 1000,
+        }
+    }
+
+    #[test]
+    fn waits_for_min_interval() {
+        assert!(!policy().allows(&location(0.0, 0), &location(500.0, 5)));
+        assert!(policy().allows(&location(0.0, 0), &location(500.0, 10)));
+    }
+
+    #[test]
+    fn waits_for_min_distance() {
+        assert!(!policy().allows(&location(0.0, 0), &location(10.0, 30)));
+        assert!(policy().allows(&location(0.0, 0), &location(30.0, 30)));
+    }
+
+    #[test]
+    fn sends_after_max_interval_without_moving() {
+        assert!(!policy().allows(&location(0.0, 0), &location(0.0, 59)));
+        assert!(policy().allows(&location(0.0, 0), &location(0.0, 60)));
+    }
+}
@@ -1,12 +1,32 @@
 #![cfg(feature = "build-binary")]
 
+mod server;
+
 use futures::prelude::*;
+use server::announcements::Announcements;
+use server::config::Config;
+use server::logging::Redacted;
+use server::metrics::Metrics;
+use server::origins::{Origins, PictureOrigin};
+use server::outbox::Outbox;
+use server::peers::Peers;
+use server::pool::PicturePool;
+use server::proofs::Proofs;
+use server::registry::{ConnectionHandle, ConnectionInfo, Registry};
+use server::subscriptions::LocationSubscriptions;
+use server::thumbnails::Thumbnails;
+use server::uploads::Uploads;
+use server::validation::LocationValidator;
+use server::visibility::VisibilityFilter;
 use std::error::Error;
-use tokio::io::AsyncReadExt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tracing::{debug, error, info, warn, Instrument};
 use trainlappcomms::*;
 use truinlag::commands::{BroadcastAction, EngineAction, EngineCommand, ResponseAction};
 use truinlag::TeamRole;
@@ -39,7 +59,7 @@ fn response_to_to_app(response: ResponseAction, player_id: u64, session_id: u64)
     use ResponseAction::*;
     match response {
         Error(err) => {
-            eprintln!("{}", err);
+            warn!("truinlag responded with an error: {}", err);
             let err = match err.try_into() {
                 Ok(err) => err,
                 Err(()) => {
@@ -67,8 +87,13 @@ fn response_to_to_app(response: ResponseAction, player_id: u64, session_id: u64)
                     TeamRole::Runner => State::Runner,
                 },
             };
+            let presence = Registry::get().presence(
+                session_id,
+                teams.iter().flat_map(|t| t.players.iter().map(|p| p.id)),
+            );
             Some(ToApp::Everything(Everything {
                 state,
+                presence,
                 teams: teams.into_iter().map(|t| t.into()).collect(),
                 events: events.into_iter().map(|e| e.into()).collect(),
                 you: player_id,
@@ -87,7 +112,7 @@ fn response_to_to_app(response: ResponseAction, player_id: u64, session_id: u64)
         UploadedPictures(_) => None,
         Period(id) => Some(ToApp::AddedPeriod(id)),
         Pictures(pics) => Some(ToApp::Pictures(
-            pics.into_iter().map(|p| p.into()).collect(),
+            pics.into_iter().map(|p| annotate(p.into())).collect(),
         )),
         SendLocations(_) => None,
         SendPastLocations { team_id, locations } => Some(ToApp::SendPastLocations {
@@ -104,6 +129,7 @@ async fn broadcast_to_to_app(
     truin_tx: &mut api::SendConnection,
     session: u64,
     team_id: usize,
+    connection: u64,
 ) -> Option<ToApp> {
     use BroadcastAction::*;
     match broadcast {
@@ -173,7 +199,10 @@ async fn broadcast_to_to_app(
                 Some(ToApp::EventOccurred(event, everything))
             }
         }
-        Pinged(mayssage) => Some(ToApp::Ping(mayssage)),
+        Pinged(Some(text)) => {
+            Some(Announcements::get().ping_announcement(session, connection, text))
+        }
+        Pinged(None) => Some(ToApp::Ping(None)),
         Ended => Some(ToApp::BecomeNoGameRunning(
             get_everything(player_id, truin_tx, session).await,
         )),
@@ -235,7 +264,25 @@ async fn broadcast_to_to_app(
 
 enum EngineCommandConversion {
     Instant(Box<EngineCommand>),
-    Delayed(std::pin::Pin<Box<dyn Future<Output = EngineCommand> + Send + Sync>>),
+    Multiple(Vec<EngineCommand>),
+    /// Handled by the connection itself, truinlag is not involved.
+    Connection(ConnectionCommand),
+    /// Already taken care of during the conversion, nothing left to do.
+    Handled,
+    /// Answered by the connection itself, truinlag is not involved.
+    Reply(ToApp),
+    /// Takes a while to handle. The answer, if any, is sent once the future completes.
+    Spawned(std::pin::Pin<Box<dyn Future<Output = Option<ToApp>> + Send>>),
+}
+
+enum ConnectionCommand {
+    SubscribeLocations {
+        teams: Vec<usize>,
+        max_rate: Option<std::time::Duration>,
+    },
+    UnsubscribeLocations(Vec<usize>),
+    SubscribeAllLocations,
+    SetLocationBatching(bool),
 }
 
 impl From<EngineCommand> for EngineCommandConversion {
@@ -249,54 +296,59 @@ fn to_server_to_engine_command(
     session: u64,
     team_id: usize,
     player_id: u64,
-) -> EngineCommandConversion {
+    validator: &mut LocationValidator,
+    truin_tx: &api::SendConnection,
+) -> Result<EngineCommandConversion, ClientError> {
     use ToServer::*;
-    match to_server {
+    Ok(match to_server {
         Login(passphrase) => EngineCommand {
             session: None,
             action: EngineAction::GetPlayerByPassphrase(passphrase),
         }
         .into(),
-        Location(location) => EngineCommand {
-            session: Some(session),
-            action: EngineAction::SendLocation {
-                player: player_id,
-                location: location.into(),
-            },
+        Location(location) => {
+            validator.check(&location)?;
+            EngineCommand {
+                session: Some(session),
+                action: EngineAction::SendLocation {
+                    player: player_id,
+                    location: location.into(),
+                },
+            }
+            .into()
         }
-        .into(),
         AttachPeriodPictures { event_id, pictures } => {
-            EngineCommandConversion::Delayed(Box::pin(async move {
-                let pictures = tokio::task::block_in_place(|| {
-                    pictures
-                        .into_iter()
-                        .filter_map(|p| RawPicture::from_bytes(p).ok())
-                        .collect()
-                });
-                EngineCommand {
-                    session: Some(session),
-                    action: EngineAction::UploadPeriodPictures {
-                        pictures,
-                        team: team_id,
-                        period: event_id,
-                    },
-                }
+            let kind = PictureKind::Period {
+                session,
+                team: team_id,
+                period_id: event_id,
+            };
+            let mut truin_tx = truin_tx.clone();
+            EngineCommandConversion::Spawned(Box::pin(async move {
+                Some(attach_pictures(&mut truin_tx, player_id, kind, event_id, pictures).await)
+            }))
+        }
+        UploadPlayerPicture(picture) => {
+            Metrics::get().picture_uploaded(picture.len());
+            server::pictures::check(&picture, &Config::get().pictures)?;
+            let kind = PictureKind::PlayerProfile(player_id);
+            let mut truin_tx = truin_tx.clone();
+            EngineCommandConversion::Spawned(Box::pin(async move {
+                rejection(store_picture(&mut truin_tx, player_id, kind, picture).await)
+            }))
+        }
+        UploadTeamPicture(picture) => {
+            Metrics::get().picture_uploaded(picture.len());
+            server::pictures::check(&picture, &Config::get().pictures)?;
+            let kind = PictureKind::TeamProfile {
+                session,
+                team: team_id,
+            };
+            let mut truin_tx = truin_tx.clone();
+            EngineCommandConversion::Spawned(Box::pin(async move {
+                rejection(store_picture(&mut truin_tx, player_id, kind, picture).await)
             }))
         }
-        UploadPlayerPicture(picture) => EngineCommandConversion::Delayed(Box::pin(async move {
-            let picture = tokio::task::block_in_place(|| RawPicture::from_bytes(picture).unwrap());
-            EngineCommand {
-                session: None,
-                action: EngineAction::UploadPlayerPicture { player_id, picture },
-            }
-        })),
-        UploadTeamPicture(picture) => EngineCommandConversion::Delayed(Box::pin(async move {
-            let picture = tokio::task::block_in_place(|| RawPicture::from_bytes(picture).unwrap());
-            EngineCommand {
-                session: Some(session),
-                action: EngineAction::UploadTeamPicture { team_id, picture },
-            }
-        })),
         Complete {
             completed_id,
             period_id,
@@ -336,11 +388,28 @@ fn to_server_to_engine_command(
             action: EngineAction::GetPictures(pictures),
         }
         .into(),
-        RequestThumbnails(thumbnails) => EngineCommand {
+        RequestThumbnails(ids) => EngineCommand {
             session: None,
-            action: EngineAction::GetThumbnails(thumbnails),
+            action: EngineAction::GetThumbnails(ids),
         }
         .into(),
+        RequestThumbnailsSized { ids, size } => {
+            if size.max_width == 0 || size.max_height == 0 {
+                return Err(ClientError::BadData(
+                    "thumbnails need a size larger than zero".into(),
+                ));
+            }
+            if ids.len() > server::thumbnails::MAX_PER_REQUEST {
+                return Err(ClientError::BadData(format!(
+                    "at most {} thumbnails can be requested at once",
+                    server::thumbnails::MAX_PER_REQUEST
+                )));
+            }
+            let mut truin_tx = truin_tx.clone();
+            EngineCommandConversion::Spawned(Box::pin(async move {
+                Some(resized_thumbnails(&mut truin_tx, ids, size).await)
+            }))
+        }
         RequestPastLocations {
             of_past_seconds,
             team_id,
@@ -352,23 +421,192 @@ fn to_server_to_engine_command(
             },
         }
         .into(),
-    }
+        LocationBatch(mut locations) => {
+            locations.sort_by_key(|l| l.timestamp);
+            let total = locations.len();
+            let commands: Vec<EngineCommand> = locations
+                .into_iter()
+                .filter(|l| validator.check(l).is_ok())
+                .map(|location| EngineCommand {
+                    session: Some(session),
+                    action: EngineAction::SendLocation {
+                        player: player_id,
+                        location: location.into(),
+                    },
+                })
+                .collect();
+            if commands.is_empty() && total > 0 {
+                return Err(ClientError::BadData(
+                    "none of the batched locations were valid".into(),
+                ));
+            } else if commands.len() < total {
+                warn!(
+                    "dropped {} of {} batched locations",
+                    total - commands.len(),
+                    total
+                );
+            }
+            EngineCommandConversion::Multiple(commands)
+        }
+        SubscribeLocations { teams, max_rate } => {
+            EngineCommandConversion::Connection(ConnectionCommand::SubscribeLocations {
+                teams,
+                max_rate,
+            })
+        }
+        UnsubscribeLocations { teams } => {
+            EngineCommandConversion::Connection(ConnectionCommand::UnsubscribeLocations(teams))
+        }
+        SetLocationBatching(batching) => {
+            EngineCommandConversion::Connection(ConnectionCommand::SetLocationBatching(batching))
+        }
+        SubscribeAllLocations => {
+            EngineCommandConversion::Connection(ConnectionCommand::SubscribeAllLocations)
+        }
+        Heartbeat(nonce) => EngineCommandConversion::Reply(ToApp::Pong(nonce)),
+        AckAnnouncement(id) => {
+            Announcements::get().acknowledge(id, session, team_id, player_id)?;
+            EngineCommandConversion::Handled
+        }
+        BeginUpload {
+            resume,
+            target,
+            size,
+        } => {
+            let limits = &Config::get().pictures;
+            let uploads = Uploads::get();
+            EngineCommandConversion::Reply(
+                match uploads.begin(session, player_id, resume, target, size, limits) {
+                    Ok((upload_id, received)) => ToApp::UploadStarted {
+                        upload_id,
+                        received,
+                    },
+                    Err(err) => ToApp::UploadFinished {
+                        upload_id: uploads.new_id(),
+                        reply: PictureReply::Rejected(err),
+                    },
+                },
+            )
+        }
+        UploadChunk {
+            upload_id,
+            offset,
+            data,
+        } => EngineCommandConversion::Reply(
+            match Uploads::get().chunk(player_id, upload_id, offset, &data, &Config::get().pictures)
+            {
+                Ok(received) => ToApp::UploadChunkAck {
+                    upload_id,
+                    received,
+                },
+                Err(err) => ToApp::UploadFinished {
+                    upload_id,
+                    reply: PictureReply::Rejected(err),
+                },
+            },
+        ),
+        FinishUpload { upload_id } => {
+            let finished = Uploads::get()
+                .finish(player_id, upload_id, &Config::get().pictures)
+                .and_then(|finished| {
+                    Metrics::get().picture_uploaded(finished.data.len());
+                    server::pictures::check(&finished.data, &Config::get().pictures)?;
+                    Ok(finished)
+                });
+            match finished {
+                Ok(finished) => {
+                    let kind = match finished.target {
+                        UploadTarget::PlayerProfile => PictureKind::PlayerProfile(player_id),
+                        UploadTarget::TeamProfile => PictureKind::TeamProfile {
+                            session,
+                            team: team_id,
+                        },
+                        UploadTarget::Period { event_id } => PictureKind::Period {
+                            session,
+                            team: team_id,
+                            period_id: event_id,
+                        },
+                    };
+                    let mut truin_tx = truin_tx.clone();
+                    EngineCommandConversion::Spawned(Box::pin(async move {
+                        Some(ToApp::UploadFinished {
+                            upload_id,
+                            reply: store_picture(&mut truin_tx, player_id, kind, finished.data)
+                                .await,
+                        })
+                    }))
+                }
+                Err(err) => {
+                    Metrics::get().picture_failed();
+                    EngineCommandConversion::Reply(ToApp::UploadFinished {
+                        upload_id,
+                        reply: PictureReply::Rejected(err),
+                    })
+                }
+            }
+        }
+    })
 }
 
-async fn handle_client(stream: TcpStream) -> Result<(), api::error::Error> {
-    let (tcp_rx, tcp_tx) = stream.into_split();
-    let mut transport_rx = FramedRead::new(tcp_rx, LengthDelimitedCodec::new());
-    let mut transport_tx = FramedWrite::new(tcp_tx, LengthDelimitedCodec::new());
+/// The next message from an app that isn't logged in yet, or None if it
+/// disconnected, sent garbage or missed the login deadline.
+async fn next_login_message(
+    transport_rx: &mut FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
+    deadline: tokio::time::Instant,
+) -> Option<ToServer> {
+    match tokio::time::timeout_at(deadline, transport_rx.next()).await {
+        Ok(Some(Ok(frame))) => match bincode::deserialize(&frame) {
+            Ok(message) => Some(message),
+            Err(err) => {
+                warn!("couldn't decode message from app: {}", err);
+                None
+            }
+        },
+        Ok(Some(Err(err))) => {
+            warn!("error receiving from app: {}", err);
+            None
+        }
+        Ok(None) => {
+            info!("app disconnected before logging in");
+            None
+        }
+        Err(_) => {
+            info!("app didn't log in in time, disconnecting");
+            None
+        }
+    }
+}
 
-    let socket = format!(
+/// Where truinlag listens, with a separate socket for debug builds.
+fn truin_socket() -> String {
+    format!(
         "/tmp/truinsocket_{}{}",
         if cfg!(debug_assertions) { "dev_" } else { "" },
         env!("CARGO_PKG_VERSION")
-    );
+    )
+}
+
+async fn handle_client(stream: TcpStream, peer: SocketAddr) -> Result<(), api::error::Error> {
+    let (tcp_rx, tcp_tx) = stream.into_split();
+    let codec = LengthDelimitedCodec::builder()
+        .max_frame_length(Config::get().connection.max_frame_bytes)
+        .new_codec();
+    let mut transport_rx = FramedRead::new(tcp_rx, codec);
+    let mut transport_tx = FramedWrite::new(tcp_tx, LengthDelimitedCodec::new());
+
+    let socket = truin_socket();
 
+    let deadline = tokio::time::Instant::now() + Config::get().connection.login_deadline;
+    // truinlag is only bothered once the app actually says something
+    let Some(mut message) = next_login_message(&mut transport_rx, deadline).await else {
+        return Ok(());
+    };
     let (mut truin_tx, truin_rx) = api::connect(Some(&socket)).await?;
-    let (internal_tx, internal_rx) = mpsc::unbounded_channel();
+    let limits = &Config::get().outbox;
+    let internal_tx = Arc::new(Outbox::new(limits));
+    let internal_rx = internal_tx.clone();
     let internal_tx_2 = internal_tx.clone();
+    let internal_tx_3 = internal_tx.clone();
 
     // the following 56 lines are ugly as all hell, please help me
     async fn login_successful(
@@ -384,12 +622,11 @@ async fn handle_client(stream: TcpStream) -> Result<(), api::error::Error> {
         .unwrap();
     }
     let (player_id, session, team_id) = loop {
-        if let ToServer::Login(passphrase) = bincode::deserialize::<trainlappcomms::ToServer>(
-            &transport_rx.next().await.unwrap().unwrap(),
-        )
-        .unwrap()
-        {
-            println!("TLC: App trying to connect with passphrase {}", passphrase);
+        if let ToServer::Login(passphrase) = message {
+            info!(
+                "app trying to log in with passphrase {}",
+                Redacted(&passphrase)
+            );
             match truin_tx
                 .send(EngineCommand {
                     session: None,
@@ -399,7 +636,7 @@ async fn handle_client(stream: TcpStream) -> Result<(), api::error::Error> {
                 .unwrap()
             {
                 ResponseAction::Player(player) => {
-                    println!("TLC: Player {} found in database", player.name);
+                    info!("player {} found in database", player.name);
                     if let Some(session) = player.session {
                         if let ResponseAction::SendState {
                             teams,
@@ -417,78 +654,158 @@ async fn handle_client(stream: TcpStream) -> Result<(), api::error::Error> {
                                 .iter()
                                 .position(|t| t.players.iter().any(|p| p.id == player.id))
                             {
-                                println!(
-                                    "TLC: Player {} found in a team, login success",
-                                    player.name
-                                );
+                                info!("player {} found in a team, login success", player.name);
                                 login_successful(&mut transport_tx, true).await;
                                 break (player.id, session, team_id);
                             }
-                            println!("TLC: Player {} not found in a team", player.name);
+                            info!("player {} not found in a team", player.name);
                             login_successful(&mut transport_tx, false).await;
                         }
-                        println!("TLC: Couldn't get state from truinlag?!??!!");
+                        error!("couldn't get state from truinlag?!??!!");
                         login_successful(&mut transport_tx, false).await;
                     } else {
-                        println!("TLC: Player {} has no session", player.name);
+                        info!("player {} has no session", player.name);
                         login_successful(&mut transport_tx, false).await;
                     }
                 }
                 _ => {
-                    println!("TLC: Player not found or found multiple times");
+                    info!("player not found or found multiple times");
                     login_successful(&mut transport_tx, false).await;
                 }
             }
         } else {
-            println!("received message from app that wasn't Login");
+            warn!("received message from app that wasn't Login");
+        }
+        let Some(next) = next_login_message(&mut transport_rx, deadline).await else {
+            return Ok(());
+        };
+        message = next;
+    };
+    let span = tracing::Span::current();
+    span.record("player", player_id);
+    span.record("team", team_id);
+    span.record("session", session);
+    let (truin_sender_tx, truin_sender_rx) = mpsc::channel(limits.command_capacity);
+    let kick = Arc::new(tokio::sync::Notify::new());
+    let registration = match Registry::get().connect(
+        session,
+        player_id,
+        team_id,
+        peer,
+        ConnectionHandle {
+            outbox: internal_tx.clone(),
+            commands: truin_sender_tx.clone(),
+            kick: kick.clone(),
+        },
+    ) {
+        Ok(registration) => registration,
+        Err(err) => {
+            error!("couldn't register connection: {}", err);
+            return Ok(());
         }
     };
+    // the outbox is still empty, so this can't overflow
+    let _ = internal_tx.push(ToApp::UploadToken(registration.token().into()));
 
+    // the truinlag sender is needed for commands that take a while on top of the channel
+    #[allow(clippy::too_many_arguments)]
     async fn app_receiver(
         mut transport_rx: FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
-        truin_sender_tx: mpsc::UnboundedSender<EngineCommand>,
+        truin_sender_tx: mpsc::Sender<EngineCommand>,
+        internal_tx: Arc<Outbox>,
+        connection_tx: mpsc::Sender<ConnectionCommand>,
+        truin_tx: api::SendConnection,
         session: u64,
         team_id: usize,
         player_id: u64,
     ) -> Result<(), Box<dyn Error>> {
         let mut count: u64 = 0;
-        while let Some(message) = transport_rx.next().await {
-            println!("({}) received message from app", count);
+        let mut validator = LocationValidator::new(&Config::get().location_limits, player_id);
+        let idle_timeout = Config::get().connection.idle_timeout;
+        loop {
+            let message = match tokio::time::timeout(idle_timeout, transport_rx.next()).await {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(_) => {
+                    info!("app was silent for {:?}, disconnecting", idle_timeout);
+                    return Ok(());
+                }
+            };
+            debug!("({}) received message from app", count);
             let message = message?;
             let message = bincode::deserialize::<trainlappcomms::ToServer>(&message).unwrap();
-            //println!("({}) message: {:?}", count, message);
-            match to_server_to_engine_command(message, session, team_id, player_id) {
-                EngineCommandConversion::Instant(command) => truin_sender_tx.send(*command)?,
-                EngineCommandConversion::Delayed(future) => {
-                    let tx = truin_sender_tx.clone();
-                    tokio::spawn(async move { tx.send(future.await).unwrap() });
+            Metrics::get().received(&message);
+            match to_server_to_engine_command(
+                message,
+                session,
+                team_id,
+                player_id,
+                &mut validator,
+                &truin_tx,
+            ) {
+                Ok(EngineCommandConversion::Instant(command)) => {
+                    truin_sender_tx.send(*command).await?
+                }
+                Ok(EngineCommandConversion::Multiple(commands)) => {
+                    for command in commands {
+                        truin_sender_tx.send(command).await?
+                    }
                 }
+                Ok(EngineCommandConversion::Connection(command)) => {
+                    connection_tx.send(command).await?
+                }
+                Ok(EngineCommandConversion::Handled) => (),
+                Ok(EngineCommandConversion::Reply(message)) => internal_tx.push(message)?,
+                Ok(EngineCommandConversion::Spawned(future)) => {
+                    let tx = internal_tx.clone();
+                    tokio::spawn(
+                        async move {
+                            if let Some(message) = future.await {
+                                // a full outbox means the connection is ending anyway
+                                let _ = tx.push(message);
+                            }
+                        }
+                        .in_current_span(),
+                    );
+                }
+                Err(err) => internal_tx.push(ToApp::Error(err))?,
             };
             count += 1;
         }
-        eprintln!("Stream returned None, client probably disconnected");
+        info!("stream returned None, client probably disconnected");
         Ok(())
     }
 
-    let (truin_sender_tx, truin_sender_rx) = mpsc::unbounded_channel();
-    let app_receiver = app_receiver(transport_rx, truin_sender_tx, session, team_id, player_id);
+    let (connection_tx, connection_rx) = mpsc::channel(limits.command_capacity);
+    let app_receiver = app_receiver(
+        transport_rx,
+        truin_sender_tx,
+        internal_tx_3,
+        connection_tx,
+        truin_tx.clone(),
+        session,
+        team_id,
+        player_id,
+    );
 
     async fn truin_sender(
-        mut rx: mpsc::UnboundedReceiver<EngineCommand>,
+        mut rx: mpsc::Receiver<EngineCommand>,
         mut truin_tx_2: api::SendConnection,
-        internal_tx_2: mpsc::UnboundedSender<ToApp>,
+        internal_tx_2: Arc<Outbox>,
         player_id: u64,
         session: u64,
     ) -> Result<(), Box<dyn Error>> {
         while let Some(command) = rx.recv().await {
+            let sent_at = std::time::Instant::now();
             match truin_tx_2.send(command).await {
                 Ok(response) => {
+                    Metrics::get().engine_latency(sent_at.elapsed());
                     if let Some(response) = response_to_to_app(response, player_id, session) {
-                        internal_tx_2.send(response)?
+                        internal_tx_2.push(response)?
                     }
                 }
                 Err(err) => {
-                    eprintln!("error sending to truinlag, stopping truin_sender: {}", err);
+                    error!("error sending to truinlag, stopping truin_sender: {}", err);
                     break;
                 }
             }
@@ -507,57 +824,163 @@ async fn handle_client(stream: TcpStream) -> Result<(), api::error::Error> {
     );
 
     async fn app_sender(
-        mut internal_rx: mpsc::UnboundedReceiver<ToApp>,
+        internal_rx: Arc<Outbox>,
+        mut connection_rx: mpsc::Receiver<ConnectionCommand>,
         mut transport_tx: FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>,
+        everything: Everything,
+        session: u64,
     ) -> Result<(), Box<dyn Error>> {
+        async fn send(
+            transport_tx: &mut FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>,
+            message: &ToApp,
+        ) -> Result<(), Box<dyn Error>> {
+            Metrics::get().sent(message);
+            // an app that doesn't read for this long is considered gone
+            tokio::time::timeout(
+                Config::get().outbox.write_timeout,
+                transport_tx.send(bincode::serialize(message)?.into()),
+            )
+            .await
+            .map_err(|_| "client stopped reading, disconnecting it")??;
+            Ok(())
+        }
+
+        let config = Config::get();
+        let policies = &config.location_policies;
+        let mut state = everything.state;
+        let mut visibility =
+            VisibilityFilter::new(config.visibility.for_session(session), &everything);
+        let mut subscriptions = LocationSubscriptions::new(config.location_window);
+        let policy = ToApp::SetLocationPolicy(policies.for_state(state).clone());
+        send(&mut transport_tx, &policy).await?;
         loop {
-            let message = internal_rx.recv().await.ok_or("fuck")?;
-            transport_tx
-                .send(bincode::serialize(&message)?.into())
-                .await?;
+            let deadline = subscriptions.next_deadline();
+            let message = tokio::select! {
+                message = internal_rx.recv() => message,
+                Some(command) = connection_rx.recv() => {
+                    match command {
+                        ConnectionCommand::SubscribeLocations { teams, max_rate } => {
+                            subscriptions.subscribe(teams, max_rate)
+                        }
+                        ConnectionCommand::UnsubscribeLocations(teams) => {
+                            subscriptions.unsubscribe(teams)
+                        }
+                        ConnectionCommand::SubscribeAllLocations => subscriptions.subscribe_all(),
+                        ConnectionCommand::SetLocationBatching(batching) => {
+                            subscriptions.set_batching(batching)
+                        }
+                    }
+                    continue;
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)),
+                    if deadline.is_some() =>
+                {
+                    for message in subscriptions.take_due() {
+                        send(&mut transport_tx, &message).await?;
+                    }
+                    continue;
+                }
+            };
+            let message = match visibility.filter(message) {
+                Some(ToApp::Location { team, location }) => {
+                    match subscriptions.offer(team, location) {
+                        Some(message) => message,
+                        None => continue,
+                    }
+                }
+                Some(message) => message,
+                None => continue,
+            };
+            let new_state = message.everything().map(|e| e.state);
+            send(&mut transport_tx, &message).await?;
+            // the role changed, so the app should send its location at a different rate
+            if let Some(new_state) = new_state.filter(|s| *s != state) {
+                state = new_state;
+                let policy = ToApp::SetLocationPolicy(policies.for_state(state).clone());
+                send(&mut transport_tx, &policy).await?;
+            }
         }
     }
 
-    let app_sender = app_sender(internal_rx, transport_tx);
+    let everything = get_everything(player_id, &mut truin_tx, session).await;
+    let app_sender = app_sender(
+        internal_rx,
+        connection_rx,
+        transport_tx,
+        everything,
+        session,
+    );
 
     async fn truin_receiver(
         truin_rx: api::InactiveRecvConnection,
-        internal_tx: mpsc::UnboundedSender<ToApp>,
+        internal_tx: Arc<Outbox>,
         player_id: u64,
         mut truin_tx: api::SendConnection,
         session: u64,
         team_id: usize,
+        connection: u64,
     ) -> Result<(), Box<dyn Error>> {
         let mut truin_rx = truin_rx.activate().await;
         loop {
             if let Some(message) = truin_rx.recv().await {
-                let to_app =
-                    broadcast_to_to_app(message, player_id, &mut truin_tx, session, team_id).await;
+                let to_app = broadcast_to_to_app(
+                    message,
+                    player_id,
+                    &mut truin_tx,
+                    session,
+                    team_id,
+                    connection,
+                )
+                .await;
                 if let Some(to_app) = to_app {
-                    internal_tx.send(to_app)?
+                    internal_tx.push(to_app)?
                 }
             }
         }
     }
 
-    let truin_receiver =
-        truin_receiver(truin_rx, internal_tx, player_id, truin_tx, session, team_id);
+    let truin_receiver = truin_receiver(
+        truin_rx,
+        internal_tx,
+        player_id,
+        truin_tx,
+        session,
+        team_id,
+        registration.id(),
+    );
 
+    let _client = Metrics::get().client_connected(session);
     let res = tokio::select! {
         res = app_sender => res,
         res = app_receiver => res,
         res = truin_receiver => res,
         res = truin_sender => res,
+        _ = kick.notified() => Err("kicked by an admin".into()),
     };
     match res {
-        Ok(_) => println!("Client disconnected"),
-        Err(err) => eprintln!("error occurred: {}", err),
+        Ok(_) => info!("client disconnected"),
+        Err(err) => warn!("client disconnected after an error: {}", err),
     }
     Ok(())
 }
 
 #[tokio::main()]
 async fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("admin") {
+        return server::admin::cli(Config::get().admin_socket.clone(), args.collect()).await;
+    }
+    server::logging::init();
+    Metrics::get().install_panic_hook();
+    // reads the proof and origin logs, which shouldn't happen on a connection's task
+    Proofs::get();
+    Origins::get();
+    if let Some(addr) = &Config::get().metrics_addr {
+        let metrics = server::metrics::bind(addr).await?;
+        tokio::spawn(server::metrics::serve(metrics));
+    }
+    let admin = server::admin::bind(&Config::get().admin_socket).await?;
+    tokio::spawn(server::admin::serve(admin));
     tokio::spawn(receive_picture_connections());
     let listener = TcpListener::bind(if cfg!(debug_assertions) {
         "192.168.1.125:42314"
@@ -565,17 +988,35 @@ async fn main() -> std::io::Result<()> {
         "192.168.1.125:41314"
     })
     .await?;
-    println!("Server listening on port 41314");
+    info!("server listening on port 41314");
 
     loop {
         let accepted = listener.accept().await;
         match accepted {
             Ok((stream, addr)) => {
-                println!("A client connected from {}", addr);
-                tokio::spawn(handle_client(stream));
+                let max = Config::get().connection.max_per_ip;
+                let Some(guard) = Peers::get().admit(addr.ip(), max) else {
+                    warn!("refused client from {}, it has too many connections", addr);
+                    continue;
+                };
+                info!("a client connected from {}", addr);
+                let span = tracing::info_span!(
+                    "client",
+                    peer = %addr,
+                    player = tracing::field::Empty,
+                    team = tracing::field::Empty,
+                    session = tracing::field::Empty,
+                );
+                tokio::spawn(
+                    async move {
+                        let _guard = guard;
+                        handle_client(stream, addr).await
+                    }
+                    .instrument(span),
+                );
             }
             Err(e) => {
-                eprintln!("Connection failed: {}", e);
+                warn!("connection failed: {}", e);
             }
         }
     }
@@ -588,79 +1029,358 @@ async fn receive_picture_connections() -> std::io::Result<()> {
         "192.168.1.125:41315"
     })
     .await?;
-    println!("Server listening for pictures on port 41315");
+    info!("server listening for pictures on port 41315");
 
     loop {
         let accepted = listener.accept().await;
         match accepted {
             Ok((stream, addr)) => {
-                println!("A picture client connected from {}", addr);
-                tokio::spawn(handle_pictures(stream));
+                info!("a picture client connected from {}", addr);
+                let span = tracing::info_span!("pictures", peer = %addr);
+                tokio::spawn(handle_pictures(stream).instrument(span));
             }
             Err(e) => {
-                eprintln!("Picture connection failed: {}", e);
+                warn!("picture connection failed: {}", e);
             }
         }
     }
 }
 
 async fn handle_pictures(mut stream: TcpStream) {
+    let limits = &Config::get().pictures;
+    // leaves room for the token and the kind next to the picture itself
+    let limit = limits.max_bytes as u64 + 4096;
     let mut buf = Vec::new();
-    stream.read_to_end(&mut buf).await.unwrap();
-    let pic = bincode::deserialize::<PictureWrapper>(&buf).unwrap();
+    let mut upload = (&mut stream).take(limit + 1);
+    // without a deadline, a client could trickle bytes and keep this going forever
+    let received =
+        match tokio::time::timeout(limits.upload_timeout, upload.read_to_end(&mut buf)).await {
+            Ok(received) => received,
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "the upload took too long",
+            )),
+        };
+    if let Err(err) = received {
+        warn!("couldn't receive picture upload: {}", err);
+        Metrics::get().picture_failed();
+        return;
+    }
+    let reply = if buf.len() as u64 > limit {
+        PictureReply::Rejected(ClientError::PictureRejected(format!(
+            "the upload is larger than {} bytes",
+            limit
+        )))
+    } else {
+        upload_picture(buf).await
+    };
+    if let PictureReply::Rejected(err) = &reply {
+        warn!("picture upload rejected: {}", err);
+        Metrics::get().picture_failed();
+    }
+    let reply = bincode::serialize(&reply).expect("replies can always be serialized");
+    if let Err(err) = async {
+        stream.write_all(&reply).await?;
+        stream.shutdown().await
+    }
+    .await
+    {
+        warn!("couldn't reply to picture upload: {}", err);
+    }
+}
+
+/// Players may only change their own profile picture and those of their team.
+fn may_upload(uploader: &ConnectionInfo, kind: &PictureKind) -> bool {
+    match *kind {
+        PictureKind::TeamProfile { session, team } | PictureKind::Period { session, team, .. } => {
+            uploader.session == session && uploader.team == team
+        }
+        PictureKind::PlayerProfile(player) => uploader.player == player,
+    }
+}
+
+async fn upload_picture(buf: Vec<u8>) -> PictureReply {
+    let pic = match bincode::deserialize::<PictureWrapper>(&buf) {
+        Ok(pic) => pic,
+        Err(err) => {
+            return PictureReply::Rejected(ClientError::BadData(format!(
+                "couldn't decode picture upload: {}",
+                err
+            )))
+        }
+    };
+    Metrics::get().picture_uploaded(pic.picture.len());
+    if let Err(err) = server::pictures::check(&pic.picture, &Config::get().pictures) {
+        return PictureReply::Rejected(err);
+    }
+    let Some(uploader) = Registry::get().authenticate(&pic.token) else {
+        info!("picture upload with unknown token {}", Redacted(&pic.token));
+        return PictureReply::Rejected(ClientError::TextError(
+            "the upload token is invalid, log in again".into(),
+        ));
+    };
     let kind = pic.kind;
-    let pic = RawPicture::from_bytes(pic.picture).unwrap();
-    let (mut truin_tx, _truin_rx) = api::connect(None).await.unwrap();
-    match kind {
-        PictureKind::TeamProfile { session, team } => {
-            println!(
-                "{:?}",
-                truin_tx
-                    .send(EngineCommand {
-                        session: Some(session),
-                        action: EngineAction::UploadTeamPicture {
-                            team_id: team,
-                            picture: pic
-                        }
-                    })
-                    .await
-                    .unwrap()
-            )
+    if !may_upload(&uploader, &kind) {
+        warn!(
+            "player {} tried to upload a picture for {:?}",
+            uploader.player, kind
+        );
+        return PictureReply::Rejected(ClientError::TextError(
+            "you may not change this picture".into(),
+        ));
+    }
+    let mut truin_tx = match api::connect(Some(&truin_socket())).await {
+        Ok((truin_tx, _truin_rx)) => truin_tx,
+        Err(err) => {
+            error!("couldn't connect to truinlag: {}", err);
+            return PictureReply::Rejected(ClientError::InternalError);
         }
-        PictureKind::PlayerProfile(player_id) => {
-            println!(
-                "{:?}",
-                truin_tx
-                    .send(EngineCommand {
-                        session: None,
-                        action: EngineAction::UploadPlayerPicture {
-                            player_id,
-                            picture: pic
+    };
+    store_picture(&mut truin_tx, uploader.player, kind, pic.picture).await
+}
+
+/// In-band uploads only get an answer if something went wrong.
+fn rejection(reply: PictureReply) -> Option<ToApp> {
+    match reply {
+        PictureReply::Uploaded(_) => None,
+        PictureReply::Rejected(err) => Some(ToApp::Error(err)),
+    }
+}
+
+/// Strips the metadata off a picture that was already checked and gets it
+/// ready for truinlag. This takes a while, so it shouldn't run on the async threads.
+fn prepare_picture(picture: &[u8]) -> Result<(RawPicture, PictureProof), ClientError> {
+    let (picture, proof) = server::pictures::sanitize(picture)?;
+    let picture = RawPicture::from_bytes(picture).map_err(|err| {
+        warn!("couldn't read uploaded picture: {}", err);
+        ClientError::PictureRejected(err.to_string())
+    })?;
+    Ok((picture, proof))
+}
+
+/// Prepares and uploads a picture that was already checked.
+async fn store_picture(
+    truin_tx: &mut api::SendConnection,
+    uploader: u64,
+    kind: PictureKind,
+    picture: Vec<u8>,
+) -> PictureReply {
+    let prepared = PicturePool::get()
+        .run(move || prepare_picture(&picture))
+        .await;
+    let uploaded = match prepared.and_then(|prepared| prepared) {
+        Ok((picture, proof)) => hand_to_truinlag(truin_tx, uploader, &kind, picture, proof).await,
+        Err(err) => Err(err),
+    };
+    match uploaded {
+        Ok(id) => PictureReply::Uploaded(id.into_iter().collect()),
+        Err(err) => PictureReply::Rejected(err),
+    }
+}
+
+/// Uploads the attached pictures that are fine and tells the app which ones
+/// weren't, by their index in the message.
+async fn attach_pictures(
+    truin_tx: &mut api::SendConnection,
+    uploader: u64,
+    kind: PictureKind,
+    event_id: usize,
+    pictures: Vec<Vec<u8>>,
+) -> ToApp {
+    let prepared = PicturePool::get()
+        .run(move || {
+            let metrics = Metrics::get();
+            let mut rejected = Vec::new();
+            let prepared: Vec<_> = pictures
+                .iter()
+                .enumerate()
+                .filter_map(|(index, picture)| {
+                    metrics.picture_uploaded(picture.len());
+                    match server::pictures::check(picture, &Config::get().pictures)
+                        .and_then(|()| prepare_picture(picture))
+                    {
+                        Ok((picture, proof)) => Some((index, picture, proof)),
+                        Err(err) => {
+                            warn!("rejected attached picture {}: {}", index, err);
+                            metrics.picture_failed();
+                            rejected.push((index, err));
+                            None
                         }
-                    })
-                    .await
-                    .unwrap()
-            )
+                    }
+                })
+                .collect();
+            (prepared, rejected)
+        })
+        .await;
+    let (prepared, mut rejected) = match prepared {
+        Ok(prepared) => prepared,
+        Err(err) => {
+            // usually TooRapid, which applies to the whole request
+            warn!("couldn't process attached pictures: {}", err);
+            return ToApp::Error(err);
+        }
+    };
+    let mut accepted = Vec::new();
+    for (index, picture, proof) in prepared {
+        match hand_to_truinlag(truin_tx, uploader, &kind, picture, proof).await {
+            Ok(id) => accepted.extend(id),
+            Err(err) => rejected.push((index, err)),
+        }
+    }
+    rejected.sort_by_key(|(index, _)| *index);
+    ToApp::PicturesAttached {
+        event_id,
+        accepted,
+        rejected,
+    }
+}
+
+/// Adds what the server knows about a picture that truinlag doesn't.
+fn annotate(mut picture: JuhuiPicture) -> JuhuiPicture {
+    picture.proof = Proofs::get().proof(picture.id);
+    Origins::get().annotate(&mut picture);
+    picture
+}
+
+/// Sends thumbnails of the requested size, resizing the pictures that aren't
+/// cached yet. Pictures truinlag doesn't know or that can't be resized are left out.
+async fn resized_thumbnails(
+    truin_tx: &mut api::SendConnection,
+    ids: Vec<u64>,
+    mut size: ThumbnailSize,
+) -> ToApp {
+    size.max_width = size.max_width.min(server::thumbnails::MAX_DIMENSION);
+    size.max_height = size.max_height.min(server::thumbnails::MAX_DIMENSION);
+    let thumbnails = Thumbnails::get();
+    let mut found = Vec::new();
+    for id in ids {
+        if let Some(data) = thumbnails.thumbnail(id, size) {
+            found.push((id, data));
+            continue;
+        }
+        let _resizing = thumbnails.lock(id, size).await;
+        // another request may have resized it while this one waited
+        if let Some(data) = thumbnails.thumbnail(id, size) {
+            found.push((id, data));
+            continue;
+        }
+        match resize_thumbnail(truin_tx, id, size).await {
+            Ok(Some(data)) => {
+                thumbnails.insert(id, size, data.clone());
+                found.push((id, data));
+            }
+            Ok(None) => (),
+            Err(err) => return ToApp::Error(err),
+        }
+    }
+    ToApp::Pictures(
+        found
+            .into_iter()
+            .map(|(id, data)| annotate(JuhuiPicture::new(id, true, data)))
+            .collect(),
+    )
+}
+
+/// Gets a picture from truinlag and resizes it on the picture pool, one
+/// picture per job so large requests don't hold up other uploads.
+async fn resize_thumbnail(
+    truin_tx: &mut api::SendConnection,
+    id: u64,
+    size: ThumbnailSize,
+) -> Result<Option<Vec<u8>>, ClientError> {
+    let response = truin_tx
+        .send(EngineCommand {
+            session: None,
+            action: EngineAction::GetPictures(vec![id]),
+        })
+        .await;
+    let picture = match response {
+        Ok(ResponseAction::Pictures(pictures)) => {
+            match pictures.into_iter().find(|picture| picture.id == id) {
+                Some(picture) => picture,
+                None => return Ok(None),
+            }
+        }
+        Ok(ResponseAction::Error(err)) => {
+            return match err.try_into() {
+                Ok(ClientError::NotFound(_)) => Ok(None),
+                Ok(err) => Err(err),
+                Err(_) => Err(ClientError::InternalError),
+            }
+        }
+        Ok(other) => {
+            error!("truinlag answered a picture request with {:?}", other);
+            return Err(ClientError::InternalError);
+        }
+        Err(err) => {
+            error!("couldn't request pictures from truinlag: {}", err);
+            return Err(ClientError::InternalError);
         }
+    };
+    let resized = PicturePool::get()
+        .run(move || server::pictures::resize(&picture.data.get_bytes(), &size))
+        .await?;
+    match resized {
+        Ok(resized) => Ok(Some(resized)),
+        Err(err) => {
+            warn!("couldn't resize picture {}: {}", id, err);
+            Ok(None)
+        }
+    }
+}
+
+/// Hands a prepared picture to truinlag, keeping its proof and origin. Returns
+/// the id of the picture, which truinlag only reports for period pictures.
+/// Pictures are sent one at a time, so each id is known to belong to its picture.
+async fn hand_to_truinlag(
+    truin_tx: &mut api::SendConnection,
+    uploader: u64,
+    kind: &PictureKind,
+    picture: RawPicture,
+    proof: PictureProof,
+) -> Result<Option<u64>, ClientError> {
+    let command = match *kind {
+        PictureKind::TeamProfile { session, team } => EngineCommand {
+            session: Some(session),
+            action: EngineAction::UploadTeamPicture {
+                team_id: team,
+                picture,
+            },
+        },
+        PictureKind::PlayerProfile(player_id) => EngineCommand {
+            session: None,
+            action: EngineAction::UploadPlayerPicture { player_id, picture },
+        },
         PictureKind::Period {
             session,
             team,
             period_id,
-        } => {
-            println!(
-                "{:?}",
-                truin_tx
-                    .send(EngineCommand {
-                        session: Some(session),
-                        action: EngineAction::UploadPeriodPictures {
-                            pictures: vec![pic],
-                            team,
-                            period: period_id
-                        }
-                    })
-                    .await
-                    .unwrap()
-            )
+        } => EngineCommand {
+            session: Some(session),
+            action: EngineAction::UploadPeriodPictures {
+                pictures: vec![picture],
+                team,
+                period: period_id,
+            },
+        },
+    };
+    let response = truin_tx.send(command).await.map_err(|err| {
+        error!("couldn't send picture to truinlag: {}", err);
+        ClientError::InternalError
+    })?;
+    info!("truinlag responded to picture upload: {:?}", response);
+    match response {
+        ResponseAction::UploadedPictures(ids) => {
+            let [id] = ids[..] else {
+                error!("truinlag answered a single picture with ids {:?}", ids);
+                return Err(ClientError::InternalError);
+            };
+            Proofs::get().record(id, proof);
+            Origins::get().record(id, PictureOrigin::new(uploader, kind));
+            Ok(Some(id))
         }
+        ResponseAction::Success => Ok(None),
+        ResponseAction::Error(err) => Err(err.try_into().unwrap_or(ClientError::InternalError)),
+        _ => Err(ClientError::InternalError),
     }
 }
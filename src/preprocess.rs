@@ -0,0 +1,179 @@
+//! Shrinking pictures on the phone before uploading them, since camera pictures
+//! are much larger than anything the apps display. Needs the `preprocess` feature.
+
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageReader, ImageResult};
+use std::io::Cursor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Jpeg,
+    /// Always lossless, the `image` crate can't encode lossy WebP. Mostly
+    /// useful for screenshots and the like, photos are smaller as JPEG.
+    WebP,
+}
+
+#[derive(Debug, Clone)]
+pub struct PreprocessOptions {
+    /// Pictures wider or higher than this are scaled down to fit.
+    pub max_dimension: u32,
+    /// Note that `quality` only applies to JPEG, WebP is always lossless.
+    pub format: OutputFormat,
+    /// JPEG quality from 1 to 100, ignored for WebP.
+    pub quality: u8,
+    /// Thumbnails are scaled down to fit into a square of this size.
+    pub thumbnail_dimension: u32,
+}
+
+impl Default for PreprocessOptions {
+    fn default() -> Self {
+        Self {
+            max_dimension: 2048,
+            format: OutputFormat::Jpeg,
+            quality: 80,
+            thumbnail_dimension: 256,
+        }
+    }
+}
+
+/// A picture ready for uploading, along with a thumbnail for showing it in
+/// the app right away.
+#[derive(Debug, Clone)]
+pub struct Preprocessed {
+    pub picture: Vec<u8>,
+    pub thumbnail: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Decodes a picture as it came from the camera, turns it upright according to
+/// its EXIF orientation, scales it down and encodes it again. The result has no
+/// EXIF data left, so it doesn't reveal anything the picture doesn't show.
+pub fn preprocess(raw: &[u8], options: &PreprocessOptions) -> ImageResult<Preprocessed> {
+    let mut decoder = ImageReader::new(Cursor::new(raw))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    if image.width() > options.max_dimension || image.height() > options.max_dimension {
+        image = image.resize(
+            options.max_dimension,
+            options.max_dimension,
+            FilterType::Lanczos3,
+        );
+    }
+    // `thumbnail` would scale small pictures up
+    let thumbnail = if image.width() > options.thumbnail_dimension
+        || image.height() > options.thumbnail_dimension
+    {
+        image.thumbnail(options.thumbnail_dimension, options.thumbnail_dimension)
+    } else {
+        image.clone()
+    };
+    Ok(Preprocessed {
+        picture: encode(&image, options)?,
+        thumbnail: encode(&thumbnail, options)?,
+        width: image.width(),
+        height: image.height(),
+    })
+}
+
+fn encode(image: &DynamicImage, options: &PreprocessOptions) -> ImageResult<Vec<u8>> {
+    // neither encoder handles every color type, but photos don't need more than this
+    let image = DynamicImage::ImageRgb8(image.to_rgb8());
+    let mut encoded = Vec::new();
+    match options.format {
+        OutputFormat::Jpeg => image
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, options.quality))?,
+        OutputFormat::WebP => image.write_with_encoder(WebPEncoder::new_lossless(&mut encoded))?,
+    }
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut encoded), ImageFormat::Jpeg)
+            .unwrap();
+        encoded
+    }
+
+    /// A JPEG with an EXIF segment holding nothing but `orientation`.
+    fn jpeg_with_orientation(width: u32, height: u32, orientation: u16) -> Vec<u8> {
+        let jpeg = jpeg(width, height);
+        let mut segment = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        segment.extend_from_slice(&orientation.to_be_bytes());
+        segment.extend_from_slice(&[0; 6]);
+        let mut with_exif = jpeg[..2].to_vec();
+        with_exif.extend_from_slice(&[0xff, 0xe1]);
+        with_exif.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+        with_exif.extend_from_slice(&segment);
+        with_exif.extend_from_slice(&jpeg[2..]);
+        with_exif
+    }
+
+    fn dimensions(encoded: &[u8]) -> (u32, u32) {
+        let image = image::load_from_memory(encoded).unwrap();
+        (image.width(), image.height())
+    }
+
+    #[test]
+    fn scales_down_to_the_maximum_dimension() {
+        let options = PreprocessOptions {
+            max_dimension: 100,
+            ..Default::default()
+        };
+        let result = preprocess(&jpeg(400, 200), &options).unwrap();
+        assert_eq!((result.width, result.height), (100, 50));
+        assert_eq!(dimensions(&result.picture), (100, 50));
+
+        let result = preprocess(&jpeg(80, 60), &options).unwrap();
+        assert_eq!(dimensions(&result.picture), (80, 60));
+    }
+
+    #[test]
+    fn turns_pictures_upright() {
+        let options = PreprocessOptions::default();
+        for orientation in [6, 8] {
+            let result = preprocess(&jpeg_with_orientation(64, 32, orientation), &options).unwrap();
+            assert_eq!((result.width, result.height), (32, 64));
+            assert_eq!(dimensions(&result.picture), (32, 64));
+        }
+        let result = preprocess(&jpeg_with_orientation(64, 32, 1), &options).unwrap();
+        assert_eq!((result.width, result.height), (64, 32));
+    }
+
+    #[test]
+    fn thumbnails_fit_their_bounds() {
+        let options = PreprocessOptions {
+            thumbnail_dimension: 50,
+            ..Default::default()
+        };
+        let result = preprocess(&jpeg(300, 120), &options).unwrap();
+        assert_eq!(dimensions(&result.thumbnail), (50, 20));
+        let result = preprocess(&jpeg(120, 300), &options).unwrap();
+        assert_eq!(dimensions(&result.thumbnail), (20, 50));
+    }
+
+    #[test]
+    fn encodes_webp() {
+        let options = PreprocessOptions {
+            format: OutputFormat::WebP,
+            ..Default::default()
+        };
+        let result = preprocess(&jpeg(40, 30), &options).unwrap();
+        assert_eq!(
+            image::guess_format(&result.picture).unwrap(),
+            ImageFormat::WebP
+        );
+        assert_eq!(dimensions(&result.thumbnail), (40, 30));
+    }
+}
@@ -0,0 +1,21 @@
+//! Server-side building blocks of the trainlappcomms binary.
+
+pub mod admin;
+pub mod announcements;
+pub mod config;
+pub mod log_file;
+pub mod logging;
+pub mod metrics;
+pub mod origins;
+pub mod outbox;
+pub mod peers;
+pub mod pictures;
+pub mod pool;
+pub mod proofs;
+pub mod recent;
+pub mod registry;
+pub mod subscriptions;
+pub mod thumbnails;
+pub mod uploads;
+pub mod validation;
+pub mod visibility;
@@ -0,0 +1,289 @@
+use super::announcements::Announcements;
+use super::registry::Registry;
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use trainlappcomms::{AnnouncementTarget, Severity};
+
+const USAGE: &str = "\
+commands:
+  list                          list connected apps
+  kick <connection>             end a connection
+  announce <session> [team <id> | player <id>] [info | warning | critical] [ack] <title> [| <body>]
+                                send an announcement to the apps in a session, or only to
+                                a team or player in it, optionally asking them to acknowledge
+  acks <announcement>           show who acknowledged an announcement
+  resync <connection>           make an app receive the current state
+  resync session <session>      make all apps in a session receive the current state";
+
+/// Binds the admin socket. The socket lives in a directory owned by the server
+/// that only it may access, so nobody else can connect in the moment before its
+/// permissions are set. Fails if another server is already listening on it.
+pub async fn bind(path: &str) -> std::io::Result<UnixListener> {
+    let path = Path::new(path);
+    if let Some(dir) = path.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        // the directory may have been there already, made by someone else
+        let metadata = std::fs::symlink_metadata(dir)?;
+        let refuse = |reason: &str| {
+            Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{} {}", dir.display(), reason),
+            ))
+        };
+        if !metadata.is_dir() {
+            return refuse("is not a directory");
+        }
+        // SAFETY: geteuid has no preconditions and can't fail
+        if metadata.uid() != unsafe { libc::geteuid() } {
+            return refuse("is owned by another user");
+        }
+        if metadata.permissions().mode() & 0o077 != 0 {
+            return refuse("is accessible to other users");
+        }
+    }
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(Error::new(
+            ErrorKind::AddrInUse,
+            format!("another server is listening on {}", path.display()),
+        ));
+    }
+    // a socket left over from an earlier run would make binding fail
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+        _ => (),
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    tracing::info!("admin socket listening on {}", path.display());
+    Ok(listener)
+}
+
+/// Listens for admin commands on the admin socket. Every connection sends a
+/// single line with a command and receives the answer as text.
+pub async fn serve(listener: UnixListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    if let Err(err) = handle_admin(stream).await {
+                        tracing::warn!("error handling admin command: {}", err);
+                    }
+                });
+            }
+            Err(err) => tracing::warn!("admin connection failed: {}", err),
+        }
+    }
+}
+
+async fn handle_admin(stream: UnixStream) -> std::io::Result<()> {
+    let (rx, mut tx) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(rx).read_line(&mut line).await?;
+    tracing::info!("admin command: {}", line.trim());
+    let answer = run(line.split_whitespace().collect());
+    tx.write_all(answer.as_bytes()).await?;
+    tx.write_all(b"\n").await?;
+    tx.shutdown().await
+}
+
+fn run(args: Vec<&str>) -> String {
+    let registry = Registry::get();
+    match args.as_slice() {
+        ["list"] => {
+            let connections = registry.list();
+            if connections.is_empty() {
+                return "no apps connected".into();
+            }
+            let mut answer = String::from("id\tsession\tplayer\tteam\tpeer\tconnected at");
+            for c in connections {
+                answer.push_str(&format!(
+                    "\n{}\t{}\t{}\t{}\t{}\t{}",
+                    c.id,
+                    c.session,
+                    c.player,
+                    c.team,
+                    c.peer,
+                    c.connected_at.to_rfc3339()
+                ));
+            }
+            answer
+        }
+        ["kick", id] => match id.parse() {
+            Ok(id) if registry.kick(id) => format!("kicked connection {}", id),
+            Ok(id) => format!("there is no connection {}", id),
+            Err(_) => format!("{} is not a connection id", id),
+        },
+        ["announce", session, rest @ ..] => match session.parse() {
+            Ok(session) => announce(session, rest),
+            Err(_) => format!("{} is not a session id", session),
+        },
+        ["acks", id] => match id.parse() {
+            Ok(id) => Announcements::get()
+                .describe(id)
+                .unwrap_or_else(|| format!("there is no announcement {}", id)),
+            Err(_) => format!("{} is not an announcement id", id),
+        },
+        ["resync", "session", session] => match session.parse() {
+            Ok(session) => format!("resyncing {} apps", registry.resync_session(session)),
+            Err(_) => format!("{} is not a session id", session),
+        },
+        ["resync", id] => match id.parse() {
+            Ok(id) if registry.resync(id) => format!("resyncing connection {}", id),
+            Ok(id) => format!("there is no connection {}", id),
+            Err(_) => format!("{} is not a connection id", id),
+        },
+        _ => USAGE.into(),
+    }
+}
+
+fn announce(session: u64, mut args: &[&str]) -> String {
+    let mut target = AnnouncementTarget::Session;
+    match args {
+        ["team", id, rest @ ..] => match id.parse() {
+            Ok(id) => {
+                target = AnnouncementTarget::Team(id);
+                args = rest;
+            }
+            Err(_) => return format!("{} is not a team id", id),
+        },
+        ["player", id, rest @ ..] => match id.parse() {
+            Ok(id) => {
+                target = AnnouncementTarget::Player(id);
+                args = rest;
+            }
+            Err(_) => return format!("{} is not a player id", id),
+        },
+        _ => (),
+    }
+    let mut severity = Severity::Info;
+    if let [level, rest @ ..] = args {
+        let level = match *level {
+            "info" => Some(Severity::Info),
+            "warning" => Some(Severity::Warning),
+            "critical" => Some(Severity::Critical),
+            _ => None,
+        };
+        if let Some(level) = level {
+            severity = level;
+            args = rest;
+        }
+    }
+    let requires_ack = args.first() == Some(&"ack");
+    if requires_ack {
+        args = &args[1..];
+    }
+    let text = args.join(" ");
+    let (title, body) = match text.split_once('|') {
+        Some((title, body)) => (title.trim(), body.trim()),
+        None => (text.as_str(), ""),
+    };
+    if title.is_empty() {
+        return USAGE.into();
+    }
+    let (id, count) = Announcements::get().announce(
+        session,
+        target,
+        title.into(),
+        body.into(),
+        severity,
+        requires_ack,
+    );
+    format!("sent announcement {} to {} apps", id, count)
+}
+
+/// Runs the command line side: sends the given command to the admin socket of
+/// a running server and prints the answer.
+pub async fn cli(path: String, args: Vec<String>) -> std::io::Result<()> {
+    if args.is_empty() {
+        println!("usage: trainlappcomms admin <command>\n{}", USAGE);
+        return Ok(());
+    }
+    let mut stream = UnixStream::connect(&path).await?;
+    stream.write_all(args.join(" ").as_bytes()).await?;
+    stream.write_all(b"\n").await?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).await?;
+    print!("{}", answer);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "trainlappcomms_admin_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn refuses_socket_in_use() {
+        let dir = temp_dir("in_use");
+        let path = dir.join("socket");
+        let path = path.to_str().unwrap();
+        let listener = bind(path).await.unwrap();
+        assert_eq!(
+            std::fs::metadata(&dir).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+        let err = bind(path).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        drop(listener);
+        // the stale socket of a server that is gone is replaced
+        assert!(bind(path).await.is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn refuses_directory_of_another_user() {
+        let dir = temp_dir("foreign");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+        // only root may give a directory away, elsewhere this can't be tested
+        if std::os::unix::fs::chown(&dir, Some(65534), None).is_err() {
+            let _ = std::fs::remove_dir_all(&dir);
+            return;
+        }
+        let path = dir.join("socket");
+        let err = bind(path.to_str().unwrap()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn refuses_symlinked_directory() {
+        let target = temp_dir("target");
+        std::fs::create_dir(&target).unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let dir = temp_dir("symlink");
+        std::os::unix::fs::symlink(&target, &dir).unwrap();
+        let path = dir.join("socket");
+        let err = bind(path.to_str().unwrap()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        let _ = std::fs::remove_file(&dir);
+        let _ = std::fs::remove_dir_all(&target);
+    }
+
+    #[tokio::test]
+    async fn refuses_shared_directory() {
+        let dir = temp_dir("shared");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let path = dir.join("socket");
+        let err = bind(path.to_str().unwrap()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,266 @@
+use super::registry::Registry;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use trainlappcomms::{AnnouncementTarget, ClientError, Severity, ToApp};
+
+/// Announcements older than the newest this many are forgotten, acks included.
+const KEPT_ANNOUNCEMENTS: usize = 1000;
+
+/// How long after a ping from truinlag other connections may still convert
+/// the same ping. Every connection receives it and converts it on its own.
+const PING_DEDUP_SECS: i64 = 10;
+
+/// Remembers sent announcements and who acknowledged them.
+pub struct Announcements {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    records: BTreeMap<u64, Record>,
+}
+
+struct Record {
+    session: u64,
+    title: String,
+    sent_at: DateTime<Utc>,
+    target: AnnouncementTarget,
+    acks: Vec<(u64, DateTime<Utc>)>,
+    /// for pings, the connections that converted this ping already
+    pinged: HashSet<u64>,
+}
+
+static ANNOUNCEMENTS: OnceLock<Announcements> = OnceLock::new();
+
+impl Announcements {
+    pub fn get() -> &'static Announcements {
+        ANNOUNCEMENTS.get_or_init(|| Announcements {
+            inner: Mutex::new(Inner::default()),
+        })
+    }
+
+    fn inner(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn record(
+        inner: &mut Inner,
+        session: u64,
+        title: &str,
+        target: AnnouncementTarget,
+        sent_at: DateTime<Utc>,
+    ) -> u64 {
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.records.insert(
+            id,
+            Record {
+                session,
+                title: title.to_string(),
+                sent_at,
+                target,
+                acks: Vec::new(),
+                pinged: HashSet::new(),
+            },
+        );
+        while inner.records.len() > KEPT_ANNOUNCEMENTS {
+            inner.records.pop_first();
+        }
+        id
+    }
+
+    /// Sends an announcement to the apps in a session matching `target`.
+    /// Returns its id and how many apps it was sent to.
+    pub fn announce(
+        &self,
+        session: u64,
+        target: AnnouncementTarget,
+        title: String,
+        body: String,
+        severity: Severity,
+        requires_ack: bool,
+    ) -> (u64, usize) {
+        let sent_at = Utc::now();
+        let id = Self::record(&mut self.inner(), session, &title, target, sent_at);
+        let message = ToApp::Announcement {
+            id,
+            title,
+            body,
+            severity,
+            sent_at,
+            target,
+            requires_ack,
+        };
+        (id, Registry::get().send_to(session, target, message))
+    }
+
+    /// Turns a ping with a message from truinlag, received by `connection`, into
+    /// an announcement. It is the same announcement as a recent ping with that
+    /// text that other connections converted, but a ping this connection already
+    /// converted means truinlag pinged again, so that's a new announcement.
+    pub fn ping_announcement(&self, session: u64, connection: u64, text: String) -> ToApp {
+        let now = Utc::now();
+        let mut inner = self.inner();
+        let existing = inner.records.iter_mut().find(|(_, r)| {
+            !r.pinged.is_empty()
+                && !r.pinged.contains(&connection)
+                && r.session == session
+                && r.title == text
+                && (now - r.sent_at).num_seconds() < PING_DEDUP_SECS
+        });
+        let (id, sent_at) = match existing {
+            Some((id, record)) => {
+                record.pinged.insert(connection);
+                (*id, record.sent_at)
+            }
+            None => {
+                let id = Self::record(&mut inner, session, &text, AnnouncementTarget::Session, now);
+                if let Some(record) = inner.records.get_mut(&id) {
+                    record.pinged.insert(connection);
+                }
+                (id, now)
+            }
+        };
+        ToApp::Announcement {
+            id,
+            title: text,
+            body: String::new(),
+            severity: Severity::Info,
+            sent_at,
+            target: AnnouncementTarget::Session,
+            requires_ack: false,
+        }
+    }
+
+    /// Records that a player acknowledged an announcement. Only players the
+    /// announcement was sent to can acknowledge it.
+    pub fn acknowledge(
+        &self,
+        id: u64,
+        session: u64,
+        team: usize,
+        player: u64,
+    ) -> Result<(), ClientError> {
+        let mut inner = self.inner();
+        match inner.records.get_mut(&id) {
+            Some(record)
+                if record.session == session
+                    && match record.target {
+                        AnnouncementTarget::Session => true,
+                        AnnouncementTarget::Team(target) => target == team,
+                        AnnouncementTarget::Player(target) => target == player,
+                    } =>
+            {
+                if !record.acks.iter().any(|(p, _)| *p == player) {
+                    record.acks.push((player, Utc::now()));
+                }
+                Ok(())
+            }
+            _ => Err(ClientError::NotFound(format!("announcement {}", id))),
+        }
+    }
+
+    /// A description of an announcement and who acknowledged it, for admins.
+    pub fn describe(&self, id: u64) -> Option<String> {
+        let inner = self.inner();
+        let record = inner.records.get(&id)?;
+        let mut description = format!(
+            "announcement {} in session {} to {:?} at {}: {}",
+            id,
+            record.session,
+            record.target,
+            record.sent_at.to_rfc3339(),
+            record.title
+        );
+        if record.acks.is_empty() {
+            description.push_str("\nnot acknowledged by anyone yet");
+        }
+        for (player, at) in &record.acks {
+            description.push_str(&format!(
+                "\nacknowledged by player {} at {}",
+                player,
+                at.to_rfc3339()
+            ));
+        }
+        Some(description)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcements() -> Announcements {
+        Announcements {
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    fn id(announcement: ToApp) -> u64 {
+        match announcement {
+            ToApp::Announcement { id, .. } => id,
+            other => panic!("not an announcement: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn same_ping_on_every_connection_is_one_announcement() {
+        let announcements = announcements();
+        let first = id(announcements.ping_announcement(1, 10, "hurry".into()));
+        let second = id(announcements.ping_announcement(1, 11, "hurry".into()));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn repeated_ping_is_a_new_announcement() {
+        let announcements = announcements();
+        let first = id(announcements.ping_announcement(1, 10, "hurry".into()));
+        let second = id(announcements.ping_announcement(1, 10, "hurry".into()));
+        assert_ne!(first, second);
+        // the other connection gets both pings in the same order
+        assert_eq!(
+            id(announcements.ping_announcement(1, 11, "hurry".into())),
+            first
+        );
+        assert_eq!(
+            id(announcements.ping_announcement(1, 11, "hurry".into())),
+            second
+        );
+    }
+
+    #[test]
+    fn pings_in_other_sessions_are_apart() {
+        let announcements = announcements();
+        let first = id(announcements.ping_announcement(1, 10, "hurry".into()));
+        let second = id(announcements.ping_announcement(2, 11, "hurry".into()));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn only_targets_acknowledge() {
+        let announcements = announcements();
+        let now = Utc::now();
+        let team = Announcements::record(
+            &mut announcements.inner(),
+            1,
+            "team",
+            AnnouncementTarget::Team(2),
+            now,
+        );
+        let player = Announcements::record(
+            &mut announcements.inner(),
+            1,
+            "player",
+            AnnouncementTarget::Player(7),
+            now,
+        );
+        assert!(announcements.acknowledge(team, 1, 2, 7).is_ok());
+        assert!(announcements.acknowledge(team, 1, 3, 8).is_err());
+        assert!(announcements.acknowledge(team, 2, 2, 7).is_err());
+        assert!(announcements.acknowledge(player, 1, 3, 7).is_ok());
+        assert!(announcements.acknowledge(player, 1, 2, 8).is_err());
+        assert_eq!(announcements.inner().records[&team].acks.len(), 1);
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use trainlappcomms::{LocationAccuracy, LocationPolicy, State};
+
+/// Runtime configuration of the server, read from `TLC_*` environment variables
+/// once at startup. Everything has a sensible default, so the server runs fine
+/// without any of them set.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub location_limits: LocationLimits,
+    pub location_policies: LocationPolicies,
+    pub visibility: VisibilityConfig,
+    pub outbox: OutboxLimits,
+    pub connection: ConnectionLimits,
+    pub pictures: PictureLimits,
+    /// Locations of a team sent to an app within this window are coalesced.
+    pub location_window: Duration,
+    /// If set, metrics are served over http on this address.
+    pub metrics_addr: Option<String>,
+    /// Path of the unix socket for `trainlappcomms admin`. Its directory is
+    /// created if needed and must not be accessible to anyone but the server.
+    pub admin_socket: String,
+}
+
+/// Limits used to decide whether a location sent by an app is plausible.
+#[derive(Debug, Clone)]
+pub struct LocationLimits {
+    /// Fixes implying a speed above this (in m/s) get flagged for review.
+    pub max_speed: f32,
+    /// How far in the future a timestamp may lie before it is rejected.
+    /// Some leeway is needed since phone clocks are never quite right.
+    pub max_future: Duration,
+    /// Fixes closer together than this aren't speed-checked, since gps jitter
+    /// over very short intervals results in absurd speeds.
+    pub min_check_interval: Duration,
+    /// If set, flagged fixes are appended to this file for game masters to review.
+    pub flag_log: Option<String>,
+}
+
+/// The location policies sent to apps depending on the role of their team.
+#[derive(Debug, Clone)]
+pub struct LocationPolicies {
+    pub runner: LocationPolicy,
+    pub catcher: LocationPolicy,
+    pub no_game: LocationPolicy,
+}
+
+impl LocationPolicies {
+    pub fn for_state(&self, state: State) -> &LocationPolicy {
+        match state {
+            State::Runner => &self.runner,
+            State::Catcher => &self.catcher,
+            State::GameNotRunning => &self.no_game,
+        }
+    }
+}
+
+/// How precisely catchers get to see where runners are. Teammates always see
+/// each other, and outside of a game everybody sees everybody.
+#[derive(Debug, Clone, Default)]
+pub struct VisibilityRules {
+    /// If set, catchers only see runners once per interval.
+    pub reveal_interval: Option<Duration>,
+    /// If set, catchers only see runner positions rounded to this many metres.
+    pub precision: Option<f32>,
+}
+
+/// Default visibility rules, optionally overridden per session with
+/// `TLC_REVEAL_INTERVAL_SECS_<session>` and `TLC_REVEAL_PRECISION_<session>`.
+#[derive(Debug, Clone)]
+pub struct VisibilityConfig {
+    pub default: VisibilityRules,
+    pub sessions: HashMap<u64, VisibilityRules>,
+}
+
+impl VisibilityConfig {
+    pub fn for_session(&self, session: u64) -> &VisibilityRules {
+        self.sessions.get(&session).unwrap_or(&self.default)
+    }
+
+    fn from_env() -> Self {
+        let default = VisibilityRules {
+            reveal_interval: env_opt::<u64>("TLC_REVEAL_INTERVAL_SECS").map(Duration::from_secs),
+            precision: env_opt("TLC_REVEAL_PRECISION"),
+        };
+        let mut sessions: HashMap<u64, VisibilityRules> = HashMap::new();
+        for (name, _) in std::env::vars() {
+            let (session, is_interval) =
+                if let Some(session) = name.strip_prefix("TLC_REVEAL_INTERVAL_SECS_") {
+                    (session, true)
+                } else if let Some(session) = name.strip_prefix("TLC_REVEAL_PRECISION_") {
+                    (session, false)
+                } else {
+                    continue;
+                };
+            let Ok(session) = session.parse() else {
+                tracing::warn!("{} doesn't end in a session id, ignoring it", name);
+                continue;
+            };
+            let rules = sessions.entry(session).or_insert_with(|| default.clone());
+            if is_interval {
+                rules.reveal_interval = env_opt::<u64>(&name).map(Duration::from_secs);
+            } else {
+                rules.precision = env_opt(&name);
+            }
+        }
+        Self { default, sessions }
+    }
+}
+
+/// Limits for the queues between the tasks handling a single app.
+#[derive(Debug, Clone)]
+pub struct OutboxLimits {
+    /// Messages waiting to be sent to an app before locations and pings get dropped.
+    pub capacity: usize,
+    /// Messages waiting to be sent to an app before it is disconnected.
+    pub disconnect_after: usize,
+    /// How long sending a single message to an app may take before it is disconnected.
+    pub write_timeout: Duration,
+    /// Commands from an app waiting for truinlag before reading from the app pauses.
+    pub command_capacity: usize,
+}
+
+/// Limits protecting the server from stuck and abusive connections.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    /// How long an app has to log in after connecting.
+    pub login_deadline: Duration,
+    /// Connections that don't send anything for this long are closed. Apps
+    /// using the library send heartbeats, so this only catches dead ones.
+    pub idle_timeout: Duration,
+    /// Connections a single ip address may have open at once, 0 for no limit.
+    /// Players on the same mobile network can share an address, so this
+    /// shouldn't be too low.
+    pub max_per_ip: usize,
+    /// Largest message an app may send, which mostly matters for pictures.
+    pub max_frame_bytes: usize,
+}
+
+/// What uploaded pictures have to look like to be accepted.
+#[derive(Debug, Clone)]
+pub struct PictureLimits {
+    pub max_bytes: usize,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub formats: Vec<image::ImageFormat>,
+    /// Chunked uploads nothing happened to for this long are forgotten.
+    pub upload_expiry: Duration,
+    /// How long receiving a picture on the picture port may take.
+    pub upload_timeout: Duration,
+    /// If set, when and where period pictures were taken is appended to this file
+    /// and read back on startup.
+    pub proof_log: Option<String>,
+    /// If set, who uploaded pictures and for what is appended to this file and
+    /// read back on startup.
+    pub origin_log: Option<String>,
+    /// Pictures decoded at once, each on its own blocking thread.
+    pub workers: usize,
+    /// Pictures that may wait for a worker before uploads are turned away.
+    pub queue: usize,
+    /// How much memory resized thumbnails may take up.
+    pub thumbnail_cache_bytes: usize,
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+impl Config {
+    fn from_env() -> Self {
+        Self {
+            location_limits: LocationLimits {
+                max_speed: env_or("TLC_MAX_SPEED", 70.0),
+                max_future: Duration::from_secs(env_or("TLC_MAX_FUTURE_SECS", 60)),
+                min_check_interval: Duration::from_secs(env_or("TLC_MIN_CHECK_INTERVAL_SECS", 5)),
+                flag_log: std::env::var("TLC_FLAG_LOG").ok(),
+            },
+            location_policies: LocationPolicies {
+                runner: policy_from_env("RUNNER", 15, 60, 25.0, LocationAccuracy::Balanced),
+                catcher: policy_from_env("CATCHER", 10, 30, 15.0, LocationAccuracy::High),
+                no_game: policy_from_env("IDLE", 60, 300, 100.0, LocationAccuracy::Low),
+            },
+            visibility: VisibilityConfig::from_env(),
+            outbox: OutboxLimits {
+                capacity: env_or("TLC_OUTBOX_CAPACITY", 256),
+                disconnect_after: env_or("TLC_OUTBOX_DISCONNECT_AFTER", 1024),
+                write_timeout: Duration::from_secs(env_or("TLC_WRITE_TIMEOUT_SECS", 30)),
+                command_capacity: env_or("TLC_COMMAND_CAPACITY", 64),
+            },
+            connection: ConnectionLimits {
+                login_deadline: Duration::from_secs(env_or("TLC_LOGIN_DEADLINE_SECS", 30)),
+                idle_timeout: Duration::from_secs(env_or("TLC_IDLE_TIMEOUT_SECS", 90)),
+                max_per_ip: env_or("TLC_MAX_CONNECTIONS_PER_IP", 32),
+                max_frame_bytes: env_or("TLC_MAX_FRAME_BYTES", 32 << 20),
+            },
+            pictures: PictureLimits {
+                max_bytes: env_or("TLC_PICTURE_MAX_BYTES", 10 << 20),
+                max_width: env_or("TLC_PICTURE_MAX_WIDTH", 8192),
+                max_height: env_or("TLC_PICTURE_MAX_HEIGHT", 8192),
+                formats: picture_formats_from_env(),
+                upload_expiry: Duration::from_secs(env_or("TLC_UPLOAD_EXPIRY_SECS", 3600)),
+                upload_timeout: Duration::from_secs(env_or("TLC_PICTURE_UPLOAD_TIMEOUT_SECS", 120)),
+                proof_log: std::env::var("TLC_PROOF_LOG").ok(),
+                origin_log: std::env::var("TLC_ORIGIN_LOG").ok(),
+                workers: env_or(
+                    "TLC_PICTURE_WORKERS",
+                    std::thread::available_parallelism().map_or(2, |n| n.get()),
+                ),
+                queue: env_or("TLC_PICTURE_QUEUE", 32),
+                thumbnail_cache_bytes: env_or("TLC_THUMBNAIL_CACHE_BYTES", 64 << 20),
+            },
+            location_window: Duration::from_millis(env_or("TLC_LOCATION_WINDOW_MS", 500)),
+            metrics_addr: std::env::var("TLC_METRICS_ADDR").ok(),
+            admin_socket: std::env::var("TLC_ADMIN_SOCKET").unwrap_or_else(|_| {
+                format!(
+                    "/tmp/trainlappcomms_admin_{}{}/socket",
+                    if cfg!(debug_assertions) { "dev_" } else { "" },
+                    env!("CARGO_PKG_VERSION")
+                )
+            }),
+        }
+    }
+
+    /// Returns the global config, reading it from the environment on first use.
+    pub fn get() -> &'static Config {
+        CONFIG.get_or_init(Self::from_env)
+    }
+}
+
+const DEFAULT_PICTURE_FORMATS: [image::ImageFormat; 3] = [
+    image::ImageFormat::Jpeg,
+    image::ImageFormat::Png,
+    image::ImageFormat::WebP,
+];
+
+/// Reads `TLC_PICTURE_FORMATS`, a comma separated list of jpeg, png and webp.
+/// All three are allowed by default.
+fn picture_formats_from_env() -> Vec<image::ImageFormat> {
+    match std::env::var("TLC_PICTURE_FORMATS") {
+        Ok(formats) => parse_picture_formats(&formats),
+        Err(_) => DEFAULT_PICTURE_FORMATS.to_vec(),
+    }
+}
+
+/// Falls back to the default formats if none of `formats` are supported,
+/// since turning away every upload is never what was meant.
+fn parse_picture_formats(formats: &str) -> Vec<image::ImageFormat> {
+    let mut parsed = Vec::new();
+    for format in formats.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        match image::ImageFormat::from_extension(format) {
+            Some(f) if DEFAULT_PICTURE_FORMATS.contains(&f) => parsed.push(f),
+            _ => tracing::warn!("ignoring unsupported picture format {:?}", format),
+        }
+    }
+    if parsed.is_empty() {
+        tracing::warn!(
+            "no supported picture formats in {:?}, allowing jpeg, png and webp",
+            formats
+        );
+        return DEFAULT_PICTURE_FORMATS.to_vec();
+    }
+    parsed
+}
+
+/// Reads `TLC_<ROLE>_LOCATION_INTERVAL_SECS`, `TLC_<ROLE>_LOCATION_MAX_INTERVAL_SECS`,
+/// `TLC_<ROLE>_LOCATION_DISTANCE` and `TLC_<ROLE>_LOCATION_ACCURACY` (low,
+/// balanced or high).
+fn policy_from_env(
+    role: &str,
+    interval: u64,
+    max_interval: u64,
+    distance: f32,
+    accuracy: LocationAccuracy,
+) -> LocationPolicy {
+    let accuracy_var = format!("TLC_{}_LOCATION_ACCURACY", role);
+    LocationPolicy {
+        min_interval: Duration::from_secs(env_or(
+            &format!("TLC_{}_LOCATION_INTERVAL_SECS", role),
+            interval,
+        )),
+        max_interval: Duration::from_secs(env_or(
+            &format!("TLC_{}_LOCATION_MAX_INTERVAL_SECS", role),
+            max_interval,
+        )),
+        min_distance: env_or(&format!("TLC_{}_LOCATION_DISTANCE", role), distance),
+        accuracy: match std::env::var(&accuracy_var).as_deref() {
+            Ok("low") => LocationAccuracy::Low,
+            Ok("balanced") => LocationAccuracy::Balanced,
+            Ok("high") => LocationAccuracy::High,
+            Ok(other) => {
+                tracing::warn!("couldn't parse {}={}, using default", accuracy_var, other);
+                accuracy
+            }
+            Err(_) => accuracy,
+        },
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    env_opt(name).unwrap_or(default)
+}
+
+fn env_opt<T: FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            tracing::warn!("couldn't parse {}={}, using default", name, value);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::ImageFormat;
+
+    #[test]
+    fn parses_picture_formats() {
+        assert_eq!(
+            parse_picture_formats("jpeg, webp"),
+            [ImageFormat::Jpeg, ImageFormat::WebP]
+        );
+        assert_eq!(parse_picture_formats("png,gif"), [ImageFormat::Png]);
+    }
+
+    #[test]
+    fn falls_back_to_default_picture_formats() {
+        assert_eq!(parse_picture_formats(""), DEFAULT_PICTURE_FORMATS);
+        assert_eq!(parse_picture_formats("gif, tiff"), DEFAULT_PICTURE_FORMATS);
+    }
+}
@@ -0,0 +1,59 @@
+use super::recent::Recent;
+use std::io::{BufRead, Write};
+
+/// Reads the newest `capacity` entries of a log with one line per id, skipping
+/// lines `parse` can't make sense of. A missing file is just empty.
+pub fn load<V>(path: &str, capacity: usize, parse: impl Fn(&str) -> Option<(u64, V)>) -> Recent<V> {
+    let mut entries = Recent::new(capacity);
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return entries,
+        Err(err) => {
+            tracing::error!("couldn't open {}: {}", path, err);
+            return entries;
+        }
+    };
+    for line in std::io::BufReader::new(file).lines() {
+        match line.map(|line| parse(&line)) {
+            Ok(Some((picture, value))) => entries.insert(picture, value),
+            Ok(None) => tracing::warn!("skipping malformed line in {}", path),
+            Err(err) => {
+                tracing::error!("couldn't read {}: {}", path, err);
+                break;
+            }
+        }
+    }
+    tracing::info!("read {} entries from {}", entries.len(), path);
+    entries
+}
+
+/// Appends a line to a log on a blocking thread, since this is called from
+/// connections.
+pub fn append(path: &'static str, line: String) {
+    tokio::task::spawn_blocking(move || {
+        let written = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(err) = written {
+            tracing::error!("couldn't write to {}: {}", path, err);
+        }
+    });
+}
+
+/// An optional field of a log line, empty if there is no value.
+pub fn field<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// Parses an optional field, failing only if there is a value and it's invalid.
+pub fn parse_field<T>(
+    field: Option<&str>,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Option<Option<T>> {
+    match field.filter(|f| !f.is_empty()) {
+        Some(value) => Some(Some(parse(value)?)),
+        None => Some(None),
+    }
+}
@@ -0,0 +1,30 @@
+use tracing_subscriber::EnvFilter;
+
+/// Sets up logging. The level is taken from `TLC_LOG` (e.g. `debug` or
+/// `trainlappcomms=trace`), defaulting to `info`. With `TLC_LOG_FORMAT=json`,
+/// every line is a json object, which is easier to feed into other tools.
+pub fn init() {
+    let filter = EnvFilter::try_from_env("TLC_LOG").unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if std::env::var("TLC_LOG_FORMAT").is_ok_and(|f| f == "json") {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
+
+/// Wraps secrets like passphrases and tokens so they don't end up in the logs.
+/// Only the length is shown, which is enough to spot empty or truncated ones.
+pub struct Redacted<'a>(pub &'a str);
+
+impl std::fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<redacted, {} chars>", self.0.chars().count())
+    }
+}
+
+impl std::fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
@@ -0,0 +1,424 @@
+use super::pool::PicturePool;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use trainlappcomms::{ToApp, ToServer};
+
+/// Counters about what the server is doing, served in the Prometheus text format.
+pub struct Metrics {
+    clients: Mutex<HashMap<u64, i64>>,
+    received: Mutex<HashMap<&'static str, u64>>,
+    sent: Mutex<HashMap<&'static str, u64>>,
+    engine_latency: Histogram,
+    picture_sizes: Histogram,
+    picture_failures: AtomicU64,
+    panics: AtomicU64,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// How long a scrape may take, so idle connections to the metrics port don't
+/// pile up.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Metrics {
+    pub fn get() -> &'static Metrics {
+        METRICS.get_or_init(Metrics::new)
+    }
+
+    fn new() -> Self {
+        Metrics {
+            clients: Mutex::new(HashMap::new()),
+            received: Mutex::new(HashMap::new()),
+            sent: Mutex::new(HashMap::new()),
+            engine_latency: Histogram::new(&[
+                0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+            ]),
+            picture_sizes: Histogram::new(&[16e3, 64e3, 256e3, 512e3, 1e6, 2e6, 4e6, 8e6, 16e6]),
+            picture_failures: AtomicU64::new(0),
+            panics: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a connected app until the returned guard is dropped.
+    pub fn client_connected(&'static self, session: u64) -> ClientGuard {
+        *lock(&self.clients).entry(session).or_default() += 1;
+        ClientGuard {
+            metrics: self,
+            session,
+        }
+    }
+
+    pub fn received(&self, message: &ToServer) {
+        *lock(&self.received)
+            .entry(to_server_name(message))
+            .or_default() += 1;
+    }
+
+    pub fn sent(&self, message: &ToApp) {
+        *lock(&self.sent).entry(to_app_name(message)).or_default() += 1;
+    }
+
+    pub fn engine_latency(&self, latency: Duration) {
+        self.engine_latency.observe(latency.as_secs_f64());
+    }
+
+    pub fn picture_uploaded(&self, bytes: usize) {
+        self.picture_sizes.observe(bytes as f64);
+    }
+
+    pub fn picture_failed(&self) {
+        self.picture_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Makes panics count towards the metrics, in addition to the usual output.
+    pub fn install_panic_hook(&'static self) {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            self.panics.fetch_add(1, Ordering::Relaxed);
+            previous(info);
+        }));
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP tlc_connected_clients Apps currently connected, per session.\n");
+        out.push_str("# TYPE tlc_connected_clients gauge\n");
+        for (session, count) in lock(&self.clients).iter() {
+            let _ = writeln!(
+                out,
+                "tlc_connected_clients{{session=\"{}\"}} {}",
+                label(session),
+                count
+            );
+        }
+        for (name, help, counts) in [
+            (
+                "tlc_messages_received_total",
+                "Messages received from apps, per ToServer variant.",
+                &self.received,
+            ),
+            (
+                "tlc_messages_sent_total",
+                "Messages sent to apps, per ToApp variant.",
+                &self.sent,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+            for (variant, count) in lock(counts).iter() {
+                let _ = writeln!(out, "{}{{variant=\"{}\"}} {}", name, label(variant), count);
+            }
+        }
+        self.engine_latency.render(
+            &mut out,
+            "tlc_engine_latency_seconds",
+            "Round trip time of commands sent to truinlag.",
+        );
+        self.picture_sizes.render(
+            &mut out,
+            "tlc_picture_upload_bytes",
+            "Size of uploaded pictures.",
+        );
+        for (name, help, value) in [
+            (
+                "tlc_picture_upload_failures_total",
+                "Picture uploads that couldn't be processed.",
+                &self.picture_failures,
+            ),
+            (
+                "tlc_panics_total",
+                "Panics, usually ending a connection.",
+                &self.panics,
+            ),
+        ] {
+            let _ = writeln!(
+                out,
+                "# HELP {} {}\n# TYPE {} counter\n{} {}",
+                name,
+                help,
+                name,
+                name,
+                value.load(Ordering::Relaxed)
+            );
+        }
+        let pool = PicturePool::get();
+        for (name, help, kind, value) in [
+            (
+                "tlc_picture_jobs_queued",
+                "Pictures waiting to be decoded.",
+                "gauge",
+                pool.queued() as u64,
+            ),
+            (
+                "tlc_picture_jobs_running",
+                "Pictures being decoded.",
+                "gauge",
+                pool.running() as u64,
+            ),
+            (
+                "tlc_picture_jobs_rejected_total",
+                "Pictures turned away because too many were waiting.",
+                "counter",
+                pool.rejected(),
+            ),
+        ] {
+            let _ = writeln!(
+                out,
+                "# HELP {} {}\n# TYPE {} {}\n{} {}",
+                name, help, name, kind, name, value
+            );
+        }
+        out
+    }
+}
+
+pub struct ClientGuard {
+    metrics: &'static Metrics,
+    session: u64,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        *lock(&self.metrics.clients).entry(self.session).or_default() -= 1;
+    }
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    /// sum of all observations, stored as f64 bits
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            if value <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some((f64::from_bits(sum) + value).to_bits())
+            });
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            f64::from_bits(self.sum.load(Ordering::Relaxed))
+        );
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Escapes a label value as the Prometheus text format wants it.
+fn label(value: impl std::fmt::Display) -> String {
+    value
+        .to_string()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn to_server_name(message: &ToServer) -> &'static str {
+    use ToServer::*;
+    match message {
+        Login(_) => "Login",
+        Location(_) => "Location",
+        AttachPeriodPictures { .. } => "AttachPeriodPictures",
+        UploadPlayerPicture(_) => "UploadPlayerPicture",
+        UploadTeamPicture(_) => "UploadTeamPicture",
+        Complete { .. } => "Complete",
+        Catch { .. } => "Catch",
+        RequestEverything => "RequestEverything",
+        Ping(_) => "Ping",
+        RequestPictures(_) => "RequestPictures",
+        RequestThumbnails(_) => "RequestThumbnails",
+        RequestPastLocations { .. } => "RequestPastLocations",
+        LocationBatch(_) => "LocationBatch",
+        SubscribeLocations { .. } => "SubscribeLocations",
+        UnsubscribeLocations { .. } => "UnsubscribeLocations",
+        SetLocationBatching(_) => "SetLocationBatching",
+        AckAnnouncement(_) => "AckAnnouncement",
+        BeginUpload { .. } => "BeginUpload",
+        UploadChunk { .. } => "UploadChunk",
+        FinishUpload { .. } => "FinishUpload",
+        SubscribeAllLocations => "SubscribeAllLocations",
+        Heartbeat(_) => "Heartbeat",
+        RequestThumbnailsSized { .. } => "RequestThumbnailsSized",
+    }
+}
+
+fn to_app_name(message: &ToApp) -> &'static str {
+    use ToApp::*;
+    match message {
+        Everything(_) => "Everything",
+        LoginSuccessful(_) => "LoginSuccessful",
+        Ping(_) => "Ping",
+        BecomeCatcher(_) => "BecomeCatcher",
+        BecomeRunner(_) => "BecomeRunner",
+        ChallengeCompleted(..) => "ChallengeCompleted",
+        BecomeNoGameRunning(_) => "BecomeNoGameRunning",
+        BecomeShutDown => "BecomeShutDown",
+        Location { .. } => "Location",
+        AddedPeriod(_) => "AddedPeriod",
+        Pictures(_) => "Pictures",
+        Error(_) => "Error",
+        SendPastLocations { .. } => "SendPastLocations",
+        GameStarted(_) => "GameStarted",
+        EventOccurred(..) => "EventOccurred",
+        YouLeftGracePeriod(_) => "YouLeftGracePeriod",
+        SetLocationPolicy(_) => "SetLocationPolicy",
+        Locations(_) => "Locations",
+        Presence { .. } => "Presence",
+        Announcement { .. } => "Announcement",
+        UploadToken(_) => "UploadToken",
+        UploadStarted { .. } => "UploadStarted",
+        UploadChunkAck { .. } => "UploadChunkAck",
+        UploadFinished { .. } => "UploadFinished",
+        PicturesAttached { .. } => "PicturesAttached",
+        Pong(_) => "Pong",
+    }
+}
+
+/// Binds the listener for serving metrics over http on `addr`.
+pub async fn bind(addr: &str) -> std::io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("serving metrics on http://{}/metrics", addr);
+    Ok(listener)
+}
+
+/// Serves the metrics over http on a listener from `bind`.
+pub async fn serve(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    match tokio::time::timeout(SCRAPE_TIMEOUT, respond(stream)).await {
+                        Ok(Ok(())) => (),
+                        Ok(Err(err)) => tracing::warn!("error serving metrics: {}", err),
+                        Err(_) => tracing::warn!("metrics request timed out"),
+                    }
+                });
+            }
+            Err(err) => tracing::warn!("metrics connection failed: {}", err),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut request = [0; 1024];
+    let read = stream.read(&mut request).await?;
+    let request = String::from_utf8_lossy(&request[..read]);
+    let response = if request.starts_with("GET /metrics ") || request.starts_with("GET / ") {
+        let body = Metrics::get().render();
+        format!(
+            "HTTP/1.1 200 OK\r\n\
+            Content-Type: text/plain; version=0.0.4\r\n\
+            Content-Length: {}\r\n\
+            Connection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_are_cumulative() {
+        let histogram = Histogram::new(&[1.0, 2.0]);
+        for value in [0.5, 1.5, 3.0] {
+            histogram.observe(value);
+        }
+        let mut out = String::new();
+        histogram.render(&mut out, "test_seconds", "A test.");
+        assert_eq!(
+            out,
+            "# HELP test_seconds A test.\n\
+            # TYPE test_seconds histogram\n\
+            test_seconds_bucket{le=\"1\"} 1\n\
+            test_seconds_bucket{le=\"2\"} 2\n\
+            test_seconds_bucket{le=\"+Inf\"} 3\n\
+            test_seconds_sum 5\n\
+            test_seconds_count 3\n"
+        );
+    }
+
+    #[test]
+    fn renders_counters_and_histograms() {
+        let metrics = Metrics::new();
+        metrics.received(&ToServer::Ping(None));
+        metrics.received(&ToServer::Ping(None));
+        metrics.sent(&ToApp::Pong(1));
+        metrics.engine_latency(Duration::from_millis(20));
+        metrics.picture_failed();
+        let out = metrics.render();
+        for line in [
+            "tlc_messages_received_total{variant=\"Ping\"} 2",
+            "tlc_messages_sent_total{variant=\"Pong\"} 1",
+            "tlc_engine_latency_seconds_bucket{le=\"0.01\"} 0",
+            "tlc_engine_latency_seconds_bucket{le=\"0.025\"} 1",
+            "tlc_engine_latency_seconds_bucket{le=\"+Inf\"} 1",
+            "tlc_engine_latency_seconds_sum 0.02",
+            "tlc_engine_latency_seconds_count 1",
+            "tlc_picture_upload_failures_total 1",
+            "# TYPE tlc_picture_upload_bytes histogram",
+        ] {
+            assert!(
+                out.lines().any(|l| l == line),
+                "{:?} missing in\n{}",
+                line,
+                out
+            );
+        }
+    }
+
+    #[test]
+    fn escapes_labels() {
+        assert_eq!(label("Ping"), "Ping");
+        assert_eq!(label(12), "12");
+        assert_eq!(label("a \"b\"\\c\nd"), "a \\\"b\\\"\\\\c\\nd");
+    }
+}
@@ -0,0 +1,150 @@
+use super::config::Config;
+use super::log_file::{self, field, parse_field};
+use super::recent::Recent;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use trainlappcomms::{JuhuiPicture, PictureKind};
+
+/// Origins kept in memory. Older ones are only in the origin log, if there is one.
+const MAX_ORIGINS: usize = 10_000;
+
+/// Who uploaded a picture, for what and when. Truinlag doesn't keep any of
+/// this, so it is remembered here.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PictureOrigin {
+    pub uploader: u64,
+    pub team: Option<usize>,
+    pub period: Option<usize>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl PictureOrigin {
+    pub fn new(uploader: u64, kind: &PictureKind) -> Self {
+        let (team, period) = match *kind {
+            PictureKind::TeamProfile { team, .. } => (Some(team), None),
+            PictureKind::PlayerProfile(_) => (None, None),
+            PictureKind::Period {
+                team, period_id, ..
+            } => (Some(team), Some(period_id)),
+        };
+        Self {
+            uploader,
+            team,
+            period,
+            created_at: chrono::Utc::now(),
+        }
+    }
+}
+
+/// Origins of uploaded pictures by picture id. If `TLC_ORIGIN_LOG` is set,
+/// they are appended to it and the newest ones are read back on startup.
+pub struct Origins {
+    origins: Mutex<Recent<PictureOrigin>>,
+    log: Option<&'static str>,
+}
+
+static ORIGINS: OnceLock<Origins> = OnceLock::new();
+
+impl Origins {
+    /// Returns the origins, reading the origin log on first use. Call this
+    /// before accepting connections, so that doesn't happen on an async thread.
+    pub fn get() -> &'static Origins {
+        ORIGINS.get_or_init(|| {
+            let log = Config::get().pictures.origin_log.as_deref();
+            Origins {
+                origins: Mutex::new(match log {
+                    Some(path) => log_file::load(path, MAX_ORIGINS, parse_line),
+                    None => Recent::new(MAX_ORIGINS),
+                }),
+                log,
+            }
+        })
+    }
+
+    fn origins(&self) -> MutexGuard<'_, Recent<PictureOrigin>> {
+        self.origins.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn record(&self, picture: u64, origin: PictureOrigin) {
+        if let Some(path) = self.log {
+            log_file::append(path, format_line(picture, &origin));
+        }
+        self.origins().insert(picture, origin);
+    }
+
+    /// Fills in what is known about where a picture came from.
+    pub fn annotate(&self, picture: &mut JuhuiPicture) {
+        if let Some(origin) = self.origins().get(picture.id) {
+            picture.uploader = Some(origin.uploader);
+            picture.team = origin.team;
+            picture.period = origin.period;
+            picture.created_at = Some(origin.created_at);
+        }
+    }
+}
+
+/// A line of the origin log: the picture id, the uploader, the team, the period
+/// and the upload time, separated by tabs. Unknown values are left empty.
+fn format_line(picture: u64, origin: &PictureOrigin) -> String {
+    format!(
+        "{}\t{}\t{}\t{}\t{}",
+        picture,
+        origin.uploader,
+        field(origin.team),
+        field(origin.period),
+        origin.created_at.to_rfc3339()
+    )
+}
+
+fn parse_line(line: &str) -> Option<(u64, PictureOrigin)> {
+    let mut fields = line.split('\t');
+    let picture = fields.next()?.parse().ok()?;
+    let uploader = fields.next()?.parse().ok()?;
+    let team = parse_field(fields.next(), |team| team.parse().ok())?;
+    let period = parse_field(fields.next(), |period| period.parse().ok())?;
+    let created_at = chrono::DateTime::parse_from_rfc3339(fields.next()?)
+        .ok()?
+        .with_timezone(&chrono::Utc);
+    Some((
+        picture,
+        PictureOrigin {
+            uploader,
+            team,
+            period,
+            created_at,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_lines_round_trip() {
+        let origin = PictureOrigin::new(
+            3,
+            &PictureKind::Period {
+                session: 1,
+                team: 2,
+                period_id: 5,
+            },
+        );
+        let line = format_line(42, &origin);
+        assert_eq!(parse_line(&line), Some((42, origin)));
+    }
+
+    #[test]
+    fn profile_pictures_have_no_period() {
+        let origin = PictureOrigin::new(3, &PictureKind::PlayerProfile(3));
+        let line = format_line(1, &origin);
+        let (_, parsed) = parse_line(&line).unwrap();
+        assert_eq!(parsed.team, None);
+        assert_eq!(parsed.period, None);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(parse_line("1\tsomeone\t\t\t2024-06-01T12:00:00Z"), None);
+        assert_eq!(parse_line("1\t3\t\t\tyesterday"), None);
+    }
+}
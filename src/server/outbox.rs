@@ -0,0 +1,193 @@
+use super::config::OutboxLimits;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use trainlappcomms::ToApp;
+
+/// The queue of messages waiting to be sent to a single app. Unlike an unbounded
+/// channel, it doesn't grow forever if the app stops reading: stale locations are
+/// replaced or dropped, and if too many messages that can't be dropped pile up,
+/// the app is considered too slow and gets disconnected.
+pub struct Outbox {
+    queue: Mutex<VecDeque<ToApp>>,
+    notify: Notify,
+    limits: &'static OutboxLimits,
+}
+
+#[derive(Debug)]
+pub struct Overflow(usize);
+
+impl std::fmt::Display for Overflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "client is too slow, {} messages are waiting to be sent",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for Overflow {}
+
+enum DropPolicy {
+    /// Only the newest message per team matters.
+    Coalesce(usize),
+    /// May be dropped when the queue is full.
+    Droppable,
+    /// Must never be dropped.
+    Keep,
+}
+
+fn drop_policy(message: &ToApp) -> DropPolicy {
+    match message {
+        ToApp::Location { team, location: _ } => DropPolicy::Coalesce(*team),
+        ToApp::Ping(_) | ToApp::Pong(_) | ToApp::Locations(_) => DropPolicy::Droppable,
+        _ => DropPolicy::Keep,
+    }
+}
+
+impl Outbox {
+    pub fn new(limits: &'static OutboxLimits) -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            limits,
+        }
+    }
+
+    /// Queues a message. Fails if the app is too slow and should be disconnected.
+    pub fn push(&self, message: ToApp) -> Result<(), Overflow> {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        match drop_policy(&message) {
+            DropPolicy::Coalesce(team) => {
+                let queued = queue
+                    .iter_mut()
+                    .find(|m| matches!(m, ToApp::Location { team: t, location: _ } if *t == team));
+                if let Some(queued) = queued {
+                    *queued = message;
+                    return Ok(());
+                } else if queue.len() >= self.limits.capacity {
+                    return Ok(());
+                }
+            }
+            DropPolicy::Droppable => {
+                if queue.len() >= self.limits.capacity {
+                    return Ok(());
+                }
+            }
+            DropPolicy::Keep => {
+                if queue.len() >= self.limits.capacity {
+                    // make room by getting rid of messages that don't matter as much
+                    queue.retain(|m| matches!(drop_policy(m), DropPolicy::Keep));
+                }
+                if queue.len() >= self.limits.disconnect_after {
+                    return Err(Overflow(queue.len()));
+                }
+            }
+        }
+        queue.push_back(message);
+        drop(queue);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Waits for the next message. Meant to be used by a single consumer.
+    pub async fn recv(&self) -> ToApp {
+        loop {
+            if let Some(message) = self
+                .queue
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .pop_front()
+            {
+                return message;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use trainlappcomms::{ClientError, DetailedLocation};
+
+    fn outbox(capacity: usize, disconnect_after: usize) -> Outbox {
+        Outbox::new(Box::leak(Box::new(OutboxLimits {
+            capacity,
+            disconnect_after,
+            write_timeout: Duration::from_secs(10),
+            command_capacity: 16,
+        })))
+    }
+
+    fn location(team: usize, timestamp: i64) -> ToApp {
+        ToApp::Location {
+            team,
+            location: DetailedLocation {
+                latitude: 47.0,
+                longitude: 8.0,
+                accuracy: 5,
+                heading: 0.0,
+                speed: 0.0,
+                timestamp,
+            },
+        }
+    }
+
+    fn important() -> ToApp {
+        ToApp::Error(ClientError::TooRapid)
+    }
+
+    fn queued(outbox: &Outbox) -> Vec<ToApp> {
+        outbox.queue.lock().unwrap().iter().cloned().collect()
+    }
+
+    #[tokio::test]
+    async fn coalesces_locations_per_team() {
+        let outbox = outbox(10, 20);
+        outbox.push(location(1, 1)).unwrap();
+        outbox.push(location(2, 1)).unwrap();
+        outbox.push(location(1, 2)).unwrap();
+        assert!(matches!(
+            outbox.recv().await,
+            ToApp::Location { team: 1, location } if location.timestamp == 2
+        ));
+        assert!(matches!(
+            outbox.recv().await,
+            ToApp::Location { team: 2, .. }
+        ));
+    }
+
+    #[test]
+    fn drops_unimportant_messages_when_full() {
+        let outbox = outbox(2, 10);
+        outbox.push(important()).unwrap();
+        outbox.push(ToApp::Ping(None)).unwrap();
+        outbox.push(ToApp::Pong(1)).unwrap();
+        outbox.push(location(1, 1)).unwrap();
+        assert_eq!(queued(&outbox).len(), 2);
+        assert!(matches!(queued(&outbox)[1], ToApp::Ping(None)));
+    }
+
+    #[test]
+    fn makes_room_for_important_messages() {
+        let outbox = outbox(2, 10);
+        outbox.push(location(1, 1)).unwrap();
+        outbox.push(ToApp::Ping(None)).unwrap();
+        outbox.push(important()).unwrap();
+        let queued = queued(&outbox);
+        assert_eq!(queued.len(), 1);
+        assert!(matches!(queued[0], ToApp::Error(_)));
+    }
+
+    #[test]
+    fn overflows_with_too_many_important_messages() {
+        let outbox = outbox(2, 3);
+        for _ in 0..3 {
+            outbox.push(important()).unwrap();
+        }
+        assert!(outbox.push(important()).is_err());
+    }
+}
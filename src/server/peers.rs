@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+/// Counts open connections per ip address, so a single address can't exhaust
+/// the server.
+pub struct Peers {
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+static PEERS: OnceLock<Peers> = OnceLock::new();
+
+impl Peers {
+    pub fn get() -> &'static Peers {
+        PEERS.get_or_init(|| Peers {
+            open: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn open(&self) -> MutexGuard<'_, HashMap<IpAddr, usize>> {
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Counts a connection from `ip` until the returned guard is dropped, or
+    /// returns None if the address already has `max` connections open.
+    /// A `max` of 0 means there is no limit.
+    pub fn admit(&'static self, ip: IpAddr, max: usize) -> Option<PeerGuard> {
+        let mut open = self.open();
+        let count = open.entry(ip).or_default();
+        if max != 0 && *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(PeerGuard { peers: self, ip })
+    }
+}
+
+pub struct PeerGuard {
+    peers: &'static Peers,
+    ip: IpAddr,
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        let mut open = self.peers.open();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
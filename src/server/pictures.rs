@@ -0,0 +1,468 @@
+use super::config::PictureLimits;
+use chrono::NaiveDateTime;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use std::io::Cursor;
+use trainlappcomms::{ClientError, PictureProof, ThumbnailFormat, ThumbnailSize};
+
+/// Quality sanitized pictures are encoded with, high since they were usually
+/// compressed once already.
+const JPEG_QUALITY: u8 = 90;
+
+/// Quality of resized thumbnails, which are only looked at briefly and small.
+const THUMBNAIL_QUALITY: u8 = 80;
+
+/// Checks an uploaded picture against the limits before anything decodes it.
+/// Only the header is read, so this is cheap even for large pictures.
+pub fn check(picture: &[u8], limits: &PictureLimits) -> Result<(), ClientError> {
+    if picture.len() > limits.max_bytes {
+        return Err(problem(format!(
+            "the picture has {} bytes, at most {} are allowed",
+            picture.len(),
+            limits.max_bytes
+        )));
+    }
+    let reader = ImageReader::new(Cursor::new(picture))
+        .with_guessed_format()
+        .map_err(|e| problem(e.to_string()))?;
+    match reader.format() {
+        Some(format) if limits.formats.contains(&format) => (),
+        Some(format) => return Err(problem(format!("{:?} pictures aren't allowed", format))),
+        None => return Err(problem("the picture format wasn't recognised".into())),
+    }
+    let (width, height) = reader
+        .into_dimensions()
+        .map_err(|e| problem(e.to_string()))?;
+    if width > limits.max_width || height > limits.max_height {
+        return Err(problem(format!(
+            "the picture is {}x{} pixels, at most {}x{} are allowed",
+            width, height, limits.max_width, limits.max_height
+        )));
+    }
+    Ok(())
+}
+
+/// Decodes a picture and encodes it again, which gets rid of all metadata, like
+/// the gps position of a player's home in a profile picture. The EXIF
+/// orientation is applied first, since it would be lost too. Also returns what
+/// the metadata said about when and where the picture was taken.
+/// This takes a while, so it shouldn't run on the async threads.
+pub fn sanitize(picture: &[u8]) -> Result<(Vec<u8>, PictureProof), ClientError> {
+    let reader = ImageReader::new(Cursor::new(picture))
+        .with_guessed_format()
+        .map_err(|e| problem(e.to_string()))?;
+    let format = reader.format();
+    let mut decoder = reader.into_decoder().map_err(|e| problem(e.to_string()))?;
+    let proof = match decoder.exif_metadata() {
+        Ok(Some(exif)) => read_proof(&exif),
+        _ => PictureProof::default(),
+    };
+    let orientation = decoder.orientation().map_err(|e| problem(e.to_string()))?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|e| problem(e.to_string()))?;
+    image.apply_orientation(orientation);
+    let mut sanitized = Vec::new();
+    if format == Some(ImageFormat::Png) {
+        image.write_with_encoder(PngEncoder::new(&mut sanitized))
+    } else {
+        // lossy WebP can't be encoded, and lossless would be much larger than the original
+        DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut sanitized, JPEG_QUALITY))
+    }
+    .map_err(|e| problem(e.to_string()))?;
+    Ok((sanitized, proof))
+}
+
+/// Scales a stored picture down to fit into `size` and encodes it in the
+/// requested format. Like `sanitize`, this shouldn't run on the async threads.
+pub fn resize(picture: &[u8], size: &ThumbnailSize) -> Result<Vec<u8>, ClientError> {
+    let mut image = image::load_from_memory(picture).map_err(|e| problem(e.to_string()))?;
+    if image.width() > size.max_width || image.height() > size.max_height {
+        image = image.thumbnail(size.max_width, size.max_height);
+    }
+    let mut resized = Vec::new();
+    match size.format {
+        ThumbnailFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(
+            JpegEncoder::new_with_quality(&mut resized, THUMBNAIL_QUALITY),
+        ),
+        ThumbnailFormat::Png => image.write_with_encoder(PngEncoder::new(&mut resized)),
+        ThumbnailFormat::WebP => DynamicImage::ImageRgba8(image.to_rgba8())
+            .write_with_encoder(WebPEncoder::new_lossless(&mut resized)),
+    }
+    .map_err(|e| problem(e.to_string()))?;
+    Ok(resized)
+}
+
+const EXIF_IFD: u16 = 0x8769;
+const GPS_IFD: u16 = 0x8825;
+const DATE_TIME: u16 = 0x0132;
+const DATE_TIME_ORIGINAL: u16 = 0x9003;
+const GPS_LATITUDE_REF: u16 = 1;
+const GPS_LATITUDE: u16 = 2;
+const GPS_LONGITUDE_REF: u16 = 3;
+const GPS_LONGITUDE: u16 = 4;
+
+/// Reads the capture time and gps position from a raw EXIF block. Anything
+/// missing or malformed is left out, apps and cameras differ wildly in what
+/// they write.
+fn read_proof(exif: &[u8]) -> PictureProof {
+    let Some((tiff, ifd0)) = Tiff::new(exif).and_then(|t| Some((t, t.u32(4)? as usize))) else {
+        return PictureProof::default();
+    };
+    let taken_at = tiff
+        .pointer(ifd0, EXIF_IFD)
+        .and_then(|ifd| tiff.ascii(ifd, DATE_TIME_ORIGINAL))
+        .or_else(|| tiff.ascii(ifd0, DATE_TIME))
+        .and_then(|text| NaiveDateTime::parse_from_str(text, "%Y:%m:%d %H:%M:%S").ok());
+    let gps = tiff.pointer(ifd0, GPS_IFD);
+    // degrees, minutes and seconds, negative in the southern and western hemispheres
+    let coordinate = |tag, ref_tag, negative, max: f64| {
+        let ifd = gps?;
+        let [degrees, minutes, seconds] = [0, 1, 2].map(|i| tiff.rational(ifd, tag, i));
+        let value = degrees? + minutes? / 60.0 + seconds? / 3600.0;
+        let value = if tiff.ascii(ifd, ref_tag)? == negative {
+            -value
+        } else {
+            value
+        };
+        (value.abs() <= max).then_some(value)
+    };
+    PictureProof {
+        taken_at,
+        latitude: coordinate(GPS_LATITUDE, GPS_LATITUDE_REF, "S", 90.0),
+        longitude: coordinate(GPS_LONGITUDE, GPS_LONGITUDE_REF, "W", 180.0),
+    }
+}
+
+/// Just enough of a TIFF reader to get values out of EXIF data.
+#[derive(Clone, Copy)]
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..4)? {
+            b"MM\0*" => true,
+            b"II*\0" => false,
+            _ => return None,
+        };
+        Some(Self { data, big_endian })
+    }
+
+    fn bytes<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        self.data
+            .get(offset..offset.checked_add(N)?)?
+            .try_into()
+            .ok()
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.bytes(offset)?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.bytes(offset)?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Finds the entry with `tag` in the directory at `ifd` and returns its
+    /// type, how many values it has and where they are.
+    fn entry(&self, ifd: usize, tag: u16) -> Option<(u16, usize, usize)> {
+        let entries = self.u16(ifd)? as usize;
+        (0..entries).find_map(|i| {
+            let entry = ifd + 2 + i * 12;
+            if self.u16(entry)? != tag {
+                return None;
+            }
+            let kind = self.u16(entry + 2)?;
+            let count = self.u32(entry + 4)? as usize;
+            let size = match kind {
+                1 | 2 | 7 => 1,
+                3 => 2,
+                4 | 9 => 4,
+                5 | 10 => 8,
+                _ => return None,
+            };
+            // values that fit into the entry are stored right in it
+            let values = if size * count <= 4 {
+                entry + 8
+            } else {
+                self.u32(entry + 8)? as usize
+            };
+            Some((kind, count, values))
+        })
+    }
+
+    /// The offset of a sub-directory, like the one with the gps data.
+    fn pointer(&self, ifd: usize, tag: u16) -> Option<usize> {
+        match self.entry(ifd, tag)? {
+            (4, 1, values) => Some(self.u32(values)? as usize),
+            _ => None,
+        }
+    }
+
+    fn ascii(&self, ifd: usize, tag: u16) -> Option<&'a str> {
+        let (2, count, values) = self.entry(ifd, tag)? else {
+            return None;
+        };
+        let bytes = self.data.get(values..values.checked_add(count)?)?;
+        Some(
+            std::str::from_utf8(bytes)
+                .ok()?
+                .trim_end_matches('\0')
+                .trim(),
+        )
+    }
+
+    fn rational(&self, ifd: usize, tag: u16, index: usize) -> Option<f64> {
+        let (5, count, values) = self.entry(ifd, tag)? else {
+            return None;
+        };
+        if index >= count {
+            return None;
+        }
+        let numerator = self.u32(values + index * 8)?;
+        let denominator = self.u32(values + index * 8 + 4)?;
+        (denominator != 0).then(|| numerator as f64 / denominator as f64)
+    }
+}
+
+fn problem(reason: String) -> ClientError {
+    ClientError::PictureRejected(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::config::Config;
+    use super::*;
+
+    /// Writes EXIF data for the tests.
+    struct Exif {
+        data: Vec<u8>,
+        big_endian: bool,
+    }
+
+    impl Exif {
+        fn u16(&mut self, value: u16) {
+            let bytes = if self.big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            };
+            self.data.extend_from_slice(&bytes);
+        }
+
+        fn u32(&mut self, value: u32) {
+            let bytes = if self.big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            };
+            self.data.extend_from_slice(&bytes);
+        }
+
+        fn entry(&mut self, tag: u16, kind: u16, count: u32, value: u32) {
+            self.u16(tag);
+            self.u16(kind);
+            self.u32(count);
+            self.u32(value);
+        }
+
+        /// An ascii entry of at most 4 bytes, stored in the entry itself.
+        fn short_ascii(&mut self, tag: u16, text: &[u8; 2]) {
+            self.u16(tag);
+            self.u16(2);
+            self.u32(2);
+            self.data.extend_from_slice(text);
+            self.data.extend_from_slice(&[0, 0]);
+        }
+
+        /// EXIF data with a capture time and a gps position of 47° 22' 30" and
+        /// 8° 30' 0", in the given byte order and hemispheres.
+        fn build(big_endian: bool, north: bool, east: bool) -> Vec<u8> {
+            let mut exif = Exif {
+                data: Vec::new(),
+                big_endian,
+            };
+            exif.data
+                .extend_from_slice(if big_endian { b"MM\0*" } else { b"II*\0" });
+            exif.u32(8);
+            // ifd0 at 8, pointing to the exif ifd at 38 and the gps ifd at 76
+            exif.u16(2);
+            exif.entry(EXIF_IFD, 4, 1, 38);
+            exif.entry(GPS_IFD, 4, 1, 76);
+            exif.u32(0);
+            // exif ifd at 38, with the capture time at 56
+            exif.u16(1);
+            exif.entry(DATE_TIME_ORIGINAL, 2, 20, 56);
+            exif.u32(0);
+            exif.data.extend_from_slice(b"2024:06:01 12:30:00\0");
+            // gps ifd at 76, with the latitude at 130 and the longitude at 154
+            exif.u16(4);
+            exif.short_ascii(GPS_LATITUDE_REF, if north { b"N\0" } else { b"S\0" });
+            exif.entry(GPS_LATITUDE, 5, 3, 130);
+            exif.short_ascii(GPS_LONGITUDE_REF, if east { b"E\0" } else { b"W\0" });
+            exif.entry(GPS_LONGITUDE, 5, 3, 154);
+            exif.u32(0);
+            for value in [47, 1, 22, 1, 30, 1, 8, 1, 30, 1, 0, 1] {
+                exif.u32(value);
+            }
+            exif.data
+        }
+    }
+
+    fn assert_close(value: Option<f64>, expected: f64) {
+        let value = value.expect("value is missing");
+        assert!((value - expected).abs() < 1e-9, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn reads_little_endian_exif() {
+        let proof = read_proof(&Exif::build(false, true, true));
+        assert_eq!(
+            proof.taken_at,
+            NaiveDateTime::parse_from_str("2024-06-01 12:30:00", "%Y-%m-%d %H:%M:%S").ok()
+        );
+        assert_close(proof.latitude, 47.375);
+        assert_close(proof.longitude, 8.5);
+    }
+
+    #[test]
+    fn reads_big_endian_exif() {
+        let proof = read_proof(&Exif::build(true, true, true));
+        assert!(proof.taken_at.is_some());
+        assert_close(proof.latitude, 47.375);
+        assert_close(proof.longitude, 8.5);
+    }
+
+    #[test]
+    fn southern_and_western_hemispheres_are_negative() {
+        let proof = read_proof(&Exif::build(false, false, false));
+        assert_close(proof.latitude, -47.375);
+        assert_close(proof.longitude, -8.5);
+    }
+
+    #[test]
+    fn ignores_garbage() {
+        assert_eq!(read_proof(b""), PictureProof::default());
+        assert_eq!(read_proof(b"not exif at all"), PictureProof::default());
+        // a directory pointing past the end of the data
+        assert_eq!(read_proof(b"II*\0\xff\xff\0\0"), PictureProof::default());
+    }
+
+    #[test]
+    fn ignores_truncated_values() {
+        let mut exif = Exif::build(false, true, true);
+        exif.truncate(140);
+        let proof = read_proof(&exif);
+        assert!(proof.taken_at.is_some());
+        assert_eq!(proof.latitude, None);
+        assert_eq!(proof.longitude, None);
+    }
+
+    fn limits(max_bytes: usize, max_side: u32, formats: &[ImageFormat]) -> PictureLimits {
+        PictureLimits {
+            max_bytes,
+            max_width: max_side,
+            max_height: max_side,
+            formats: formats.to_vec(),
+            ..Config::get().pictures.clone()
+        }
+    }
+
+    fn encode(format: ImageFormat, width: u32, height: u32) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(width, height));
+        let mut encoded = Cursor::new(Vec::new());
+        image.write_to(&mut encoded, format).unwrap();
+        encoded.into_inner()
+    }
+
+    fn assert_rejected(result: Result<(), ClientError>) {
+        match result {
+            Err(ClientError::PictureRejected(reason)) => assert!(!reason.is_empty()),
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+    }
+
+    const ALL: [ImageFormat; 3] = [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP];
+
+    #[test]
+    fn accepts_pictures_within_the_limits() {
+        let limits = limits(1 << 20, 100, &ALL);
+        assert!(check(&encode(ImageFormat::Png, 100, 50), &limits).is_ok());
+        assert!(check(&encode(ImageFormat::Jpeg, 50, 100), &limits).is_ok());
+    }
+
+    #[test]
+    fn rejects_too_many_bytes() {
+        let picture = encode(ImageFormat::Png, 10, 10);
+        assert_rejected(check(&picture, &limits(picture.len() - 1, 100, &ALL)));
+    }
+
+    #[test]
+    fn rejects_too_many_pixels() {
+        let limits = limits(1 << 20, 100, &ALL);
+        assert_rejected(check(&encode(ImageFormat::Png, 101, 10), &limits));
+        assert_rejected(check(&encode(ImageFormat::Png, 10, 101), &limits));
+    }
+
+    #[test]
+    fn rejects_formats_not_allowed() {
+        let limits = limits(1 << 20, 100, &[ImageFormat::Jpeg]);
+        assert_rejected(check(&encode(ImageFormat::Png, 10, 10), &limits));
+    }
+
+    #[test]
+    fn rejects_garbage_and_truncated_headers() {
+        let limits = limits(1 << 20, 100, &ALL);
+        assert_rejected(check(b"definitely not a picture", &limits));
+        let picture = encode(ImageFormat::Png, 10, 10);
+        assert_rejected(check(&picture[..12], &limits));
+    }
+
+    /// A JPEG with an APP1 segment holding `exif`, right after the start marker.
+    fn jpeg_with_exif(exif: &[u8]) -> Vec<u8> {
+        let jpeg = encode(ImageFormat::Jpeg, 16, 8);
+        let mut segment = b"Exif\0\0".to_vec();
+        segment.extend_from_slice(exif);
+        let mut with_exif = jpeg[..2].to_vec();
+        with_exif.extend_from_slice(&[0xff, 0xe1]);
+        with_exif.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+        with_exif.extend_from_slice(&segment);
+        with_exif.extend_from_slice(&jpeg[2..]);
+        with_exif
+    }
+
+    fn contains(data: &[u8], marker: &[u8]) -> bool {
+        data.windows(marker.len()).any(|w| w == marker)
+    }
+
+    #[test]
+    fn sanitize_strips_exif_and_keeps_the_proof() {
+        let picture = jpeg_with_exif(&Exif::build(false, true, false));
+        assert!(contains(&picture, b"Exif\0\0"));
+        let (sanitized, proof) = sanitize(&picture).unwrap();
+        assert!(!contains(&sanitized, b"Exif\0\0"));
+        assert!(!contains(&sanitized, b"2024:06:01"));
+        assert_eq!(
+            proof.taken_at,
+            NaiveDateTime::parse_from_str("2024-06-01 12:30:00", "%Y-%m-%d %H:%M:%S").ok()
+        );
+        assert_close(proof.latitude, 47.375);
+        assert_close(proof.longitude, -8.5);
+        let decoded = image::load_from_memory(&sanitized).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (16, 8));
+    }
+}
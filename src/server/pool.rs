@@ -0,0 +1,162 @@
+use super::config::Config;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Semaphore;
+use trainlappcomms::ClientError;
+
+/// Blocking threads for decoding and encoding pictures. Only a few jobs run at
+/// once and only a limited number wait, so a burst of uploads after a game
+/// event can't take over the whole server.
+pub struct PicturePool {
+    workers: Arc<Semaphore>,
+    /// jobs that are running or waiting for a worker
+    pending: AtomicUsize,
+    running: AtomicUsize,
+    rejected: AtomicU64,
+    capacity: usize,
+}
+
+static POOL: OnceLock<PicturePool> = OnceLock::new();
+
+impl PicturePool {
+    pub fn get() -> &'static PicturePool {
+        POOL.get_or_init(|| {
+            let limits = &Config::get().pictures;
+            PicturePool::new(limits.workers, limits.queue)
+        })
+    }
+
+    fn new(workers: usize, queue: usize) -> Self {
+        let workers = workers.max(1);
+        PicturePool {
+            workers: Arc::new(Semaphore::new(workers)),
+            pending: AtomicUsize::new(0),
+            running: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+            capacity: workers + queue,
+        }
+    }
+
+    /// Runs a job on a blocking thread once a worker is free. If too many jobs
+    /// are waiting already, fails with `ClientError::TooRapid` right away.
+    pub async fn run<T: Send + 'static>(
+        &'static self,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, ClientError> {
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= self.capacity {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(ClientError::TooRapid);
+        }
+        let pending = Counted(&self.pending);
+        let worker = self
+            .workers
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        self.running.fetch_add(1, Ordering::SeqCst);
+        let running = Counted(&self.running);
+        // a job keeps running if whoever waits for it goes away, so it keeps its
+        // worker until it is actually done
+        tokio::task::spawn_blocking(move || {
+            let _counted = (pending, worker, running);
+            job()
+        })
+        .await
+        .map_err(|err| {
+            tracing::error!("picture job failed: {}", err);
+            ClientError::InternalError
+        })
+    }
+
+    /// Jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.pending
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.running.load(Ordering::SeqCst))
+    }
+
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Jobs turned away because the queue was full.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+/// Decrements a counter when dropped, so it stays right if a job is cancelled.
+struct Counted(&'static AtomicUsize);
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    fn pool(workers: usize, queue: usize) -> &'static PicturePool {
+        Box::leak(Box::new(PicturePool::new(workers, queue)))
+    }
+
+    /// A job that blocks until something is sent on the returned channel.
+    fn blocking_job() -> (mpsc::Sender<()>, impl FnOnce() + Send + 'static) {
+        let (release, released) = mpsc::channel();
+        (release, move || {
+            let _ = released.recv();
+        })
+    }
+
+    async fn wait_until(condition: impl Fn() -> bool) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("condition never became true");
+    }
+
+    #[tokio::test]
+    async fn turns_jobs_away_when_full() {
+        let pool = pool(1, 1);
+        let (release_first, first) = blocking_job();
+        let (release_second, second) = blocking_job();
+        let first = tokio::spawn(pool.run(first));
+        let second = tokio::spawn(pool.run(second));
+        wait_until(|| pool.running() == 1 && pool.queued() == 1).await;
+        assert!(matches!(pool.run(|| ()).await, Err(ClientError::TooRapid)));
+        assert_eq!(pool.rejected(), 1);
+        release_first.send(()).unwrap();
+        release_second.send(()).unwrap();
+        first.await.unwrap().unwrap();
+        second.await.unwrap().unwrap();
+        assert_eq!(pool.running(), 0);
+        assert_eq!(pool.queued(), 0);
+        assert!(pool.run(|| ()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn cancelled_jobs_keep_their_worker() {
+        let pool = pool(1, 4);
+        let (release, job) = blocking_job();
+        let cancelled = tokio::spawn(pool.run(job));
+        wait_until(|| pool.running() == 1).await;
+        cancelled.abort();
+        let _ = cancelled.await;
+        // the job is still decoding, so nothing else may run yet
+        assert_eq!(pool.running(), 1);
+        assert_eq!(pool.workers.available_permits(), 0);
+        release.send(()).unwrap();
+        wait_until(|| pool.running() == 0).await;
+        assert_eq!(pool.queued(), 0);
+        assert_eq!(pool.workers.available_permits(), 1);
+    }
+}
@@ -0,0 +1,122 @@
+use super::config::Config;
+use super::log_file::{self, field, parse_field};
+use super::recent::Recent;
+use chrono::NaiveDateTime;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use trainlappcomms::PictureProof;
+
+/// Proofs kept in memory. Older ones are only in the proof log, if there is one.
+const MAX_PROOFS: usize = 10_000;
+
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// What the metadata of period pictures said, by picture id. Truinlag has no
+/// place for this, so it is kept here and added when pictures are sent out.
+///
+/// If `TLC_PROOF_LOG` is set, every proof is appended to it and the newest
+/// ones are read back from it on startup, so they survive restarts.
+pub struct Proofs {
+    proofs: Mutex<Recent<PictureProof>>,
+    log: Option<&'static str>,
+}
+
+static PROOFS: OnceLock<Proofs> = OnceLock::new();
+
+impl Proofs {
+    /// Returns the proofs, reading the proof log on first use. Call this before
+    /// accepting connections, so that doesn't happen on an async thread.
+    pub fn get() -> &'static Proofs {
+        PROOFS.get_or_init(|| {
+            let log = Config::get().pictures.proof_log.as_deref();
+            Proofs {
+                proofs: Mutex::new(match log {
+                    Some(path) => log_file::load(path, MAX_PROOFS, parse_line),
+                    None => Recent::new(MAX_PROOFS),
+                }),
+                log,
+            }
+        })
+    }
+
+    fn proofs(&self) -> MutexGuard<'_, Recent<PictureProof>> {
+        self.proofs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Remembers the proof of a picture and appends it to the proof log.
+    pub fn record(&self, picture: u64, proof: PictureProof) {
+        if proof.is_empty() {
+            return;
+        }
+        if let Some(path) = self.log {
+            log_file::append(path, format_line(picture, &proof));
+        }
+        self.proofs().insert(picture, proof);
+    }
+
+    pub fn proof(&self, picture: u64) -> Option<PictureProof> {
+        self.proofs().get(picture).cloned()
+    }
+}
+
+/// A line of the proof log: the picture id, the capture time, the latitude and
+/// the longitude, separated by tabs. Unknown values are left empty.
+fn format_line(picture: u64, proof: &PictureProof) -> String {
+    format!(
+        "{}\t{}\t{}\t{}",
+        picture,
+        field(proof.taken_at.map(|t| t.format(TIME_FORMAT))),
+        field(proof.latitude),
+        field(proof.longitude)
+    )
+}
+
+fn parse_line(line: &str) -> Option<(u64, PictureProof)> {
+    let mut fields = line.split('\t');
+    let picture = fields.next()?.parse().ok()?;
+    let taken_at = parse_field(fields.next(), |time| {
+        NaiveDateTime::parse_from_str(time, TIME_FORMAT).ok()
+    })?;
+    let latitude = parse_field(fields.next(), |latitude| latitude.parse().ok())?;
+    let longitude = parse_field(fields.next(), |longitude| longitude.parse().ok())?;
+    Some((
+        picture,
+        PictureProof {
+            taken_at,
+            latitude,
+            longitude,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_lines_round_trip() {
+        let proof = PictureProof {
+            taken_at: NaiveDateTime::parse_from_str("2024-06-01T12:30:00", TIME_FORMAT).ok(),
+            latitude: Some(47.3769),
+            longitude: Some(-8.5417),
+        };
+        let line = format_line(42, &proof);
+        assert_eq!(parse_line(&line), Some((42, proof)));
+    }
+
+    #[test]
+    fn log_lines_with_missing_values() {
+        let proof = PictureProof {
+            taken_at: None,
+            latitude: Some(1.5),
+            longitude: None,
+        };
+        assert_eq!(format_line(7, &proof), "7\t\t1.5\t");
+        assert_eq!(parse_line("7\t\t1.5\t"), Some((7, proof)));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(parse_line("picture 3 taken at None"), None);
+        assert_eq!(parse_line("3\tyesterday\t\t"), None);
+    }
+}
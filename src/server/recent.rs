@@ -0,0 +1,69 @@
+use std::collections::{HashMap, VecDeque};
+
+/// A map by picture id that only keeps the most recently inserted entries,
+/// for details about pictures that would otherwise pile up forever.
+pub struct Recent<V> {
+    values: HashMap<u64, V>,
+    /// ids in the order they were first inserted, oldest first
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl<V> Recent<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            values: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn insert(&mut self, id: u64, value: V) {
+        if self.values.insert(id, value).is_none() {
+            self.order.push_back(id);
+        }
+        while self.values.len() > self.capacity {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.values.remove(&oldest);
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&V> {
+        self.values.get(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_oldest() {
+        let mut recent = Recent::new(2);
+        recent.insert(1, "a");
+        recent.insert(2, "b");
+        recent.insert(3, "c");
+        assert_eq!(recent.get(1), None);
+        assert_eq!(recent.get(2), Some(&"b"));
+        assert_eq!(recent.get(3), Some(&"c"));
+        assert_eq!(recent.len(), 2);
+    }
+
+    #[test]
+    fn replacing_keeps_position() {
+        let mut recent = Recent::new(2);
+        recent.insert(1, "a");
+        recent.insert(2, "b");
+        recent.insert(1, "c");
+        recent.insert(3, "d");
+        assert_eq!(recent.get(1), None);
+        assert_eq!(recent.get(2), Some(&"b"));
+        assert_eq!(recent.len(), 2);
+    }
+}
@@ -0,0 +1,323 @@
+use super::outbox::Outbox;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use tokio::sync::{mpsc, Notify};
+use trainlappcomms::{AnnouncementTarget, PlayerPresence, ToApp};
+use truinlag::commands::{EngineAction, EngineCommand};
+
+/// Keeps track of all apps that are currently connected, so that connections
+/// can find out about each other.
+pub struct Registry {
+    sessions: Mutex<HashMap<u64, Session>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Default)]
+struct Session {
+    connections: Vec<Connection>,
+    /// when players were last connected, for players that were connected at some point
+    last_seen: HashMap<u64, DateTime<Utc>>,
+}
+
+struct Connection {
+    id: u64,
+    info: ConnectionInfo,
+    handle: ConnectionHandle,
+    /// authenticates uploads on the picture port
+    token: String,
+}
+
+/// What the registry needs to reach a connection's tasks.
+pub struct ConnectionHandle {
+    pub outbox: Arc<Outbox>,
+    /// commands for truinlag, responses end up in the outbox
+    pub commands: mpsc::Sender<EngineCommand>,
+    /// ends the connection when notified
+    pub kick: Arc<Notify>,
+}
+
+/// A description of a connection, e.g. for listing them.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub session: u64,
+    pub player: u64,
+    pub team: usize,
+    pub peer: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+}
+
+impl Session {
+    fn is_online(&self, player: u64) -> bool {
+        self.connections.iter().any(|c| c.info.player == player)
+    }
+
+    /// Sends a message to all connections in the session. Connections that
+    /// can't keep up are ignored, they get disconnected by their own tasks.
+    fn broadcast(&self, message: ToApp) {
+        for connection in &self.connections {
+            let _ = connection.handle.outbox.push(message.clone());
+        }
+    }
+}
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+impl Registry {
+    pub fn get() -> &'static Registry {
+        REGISTRY.get_or_init(|| Registry {
+            sessions: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    fn sessions(&self) -> MutexGuard<'_, HashMap<u64, Session>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Registers a connection until the returned guard is dropped. The other
+    /// apps in the session are told that the player is online. Fails if no
+    /// upload token could be made.
+    pub fn connect(
+        &'static self,
+        session: u64,
+        player: u64,
+        team: usize,
+        peer: SocketAddr,
+        handle: ConnectionHandle,
+    ) -> std::io::Result<Registration> {
+        let token = new_token()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Utc::now();
+        let mut sessions = self.sessions();
+        let entry = sessions.entry(session).or_default();
+        let was_online = entry.is_online(player);
+        entry.last_seen.insert(player, now);
+        if !was_online {
+            entry.broadcast(ToApp::Presence {
+                player,
+                online: true,
+                last_seen: Some(now),
+            });
+        }
+        entry.connections.push(Connection {
+            id,
+            info: ConnectionInfo {
+                id,
+                session,
+                player,
+                team,
+                peer,
+                connected_at: now,
+            },
+            handle,
+            token: token.clone(),
+        });
+        Ok(Registration {
+            registry: self,
+            session,
+            id,
+            token,
+        })
+    }
+
+    fn disconnect(&self, session: u64, id: u64) {
+        let mut sessions = self.sessions();
+        let Some(entry) = sessions.get_mut(&session) else {
+            return;
+        };
+        let Some(index) = entry.connections.iter().position(|c| c.id == id) else {
+            return;
+        };
+        let player = entry.connections.remove(index).info.player;
+        if !entry.is_online(player) {
+            let now = Utc::now();
+            entry.last_seen.insert(player, now);
+            entry.broadcast(ToApp::Presence {
+                player,
+                online: false,
+                last_seen: Some(now),
+            });
+        }
+    }
+
+    /// All current connections, ordered by id.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self
+            .sessions()
+            .values()
+            .flat_map(|s| s.connections.iter().map(|c| c.info.clone()))
+            .collect();
+        connections.sort_by_key(|c| c.id);
+        connections
+    }
+
+    /// Ends the connection with the given id. Returns whether it exists.
+    pub fn kick(&self, id: u64) -> bool {
+        self.with_connection(id, |c| c.handle.kick.notify_one())
+    }
+
+    /// Makes the connection with the given id fetch and send the current state.
+    /// Returns whether it exists.
+    pub fn resync(&self, id: u64) -> bool {
+        self.with_connection(id, |c| resync(c, c.info.session))
+    }
+
+    /// Makes all connections in a session fetch and send the current state.
+    /// Returns how many connections there are.
+    pub fn resync_session(&self, session: u64) -> usize {
+        self.sessions().get(&session).map_or(0, |s| {
+            for connection in &s.connections {
+                resync(connection, session);
+            }
+            s.connections.len()
+        })
+    }
+
+    /// Sends a message to the connections in a session matching `target`.
+    /// Returns how many connections it was sent to.
+    pub fn send_to(&self, session: u64, target: AnnouncementTarget, message: ToApp) -> usize {
+        let sessions = self.sessions();
+        let Some(entry) = sessions.get(&session) else {
+            return 0;
+        };
+        let mut count = 0;
+        for connection in entry.connections.iter().filter(|c| match target {
+            AnnouncementTarget::Session => true,
+            AnnouncementTarget::Team(team) => c.info.team == team,
+            AnnouncementTarget::Player(player) => c.info.player == player,
+        }) {
+            let _ = connection.handle.outbox.push(message.clone());
+            count += 1;
+        }
+        count
+    }
+
+    /// The connection an upload token belongs to, if it is still connected.
+    pub fn authenticate(&self, token: &str) -> Option<ConnectionInfo> {
+        self.sessions()
+            .values()
+            .flat_map(|s| &s.connections)
+            .find(|c| tokens_match(&c.token, token))
+            .map(|c| c.info.clone())
+    }
+
+    fn with_connection(&self, id: u64, f: impl FnOnce(&Connection)) -> bool {
+        let sessions = self.sessions();
+        match sessions
+            .values()
+            .flat_map(|s| &s.connections)
+            .find(|c| c.id == id)
+        {
+            Some(connection) => {
+                f(connection);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The presence of the given players in a session.
+    pub fn presence(
+        &self,
+        session: u64,
+        players: impl Iterator<Item = u64>,
+    ) -> Vec<PlayerPresence> {
+        let sessions = self.sessions();
+        let entry = sessions.get(&session);
+        players
+            .map(|player| {
+                let online = entry.is_some_and(|e| e.is_online(player));
+                PlayerPresence {
+                    player,
+                    online,
+                    last_seen: if online {
+                        Some(Utc::now())
+                    } else {
+                        entry.and_then(|e| e.last_seen.get(&player).copied())
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+/// A connection in the registry, removed again once this is dropped.
+pub struct Registration {
+    registry: &'static Registry,
+    session: u64,
+    id: u64,
+    token: String,
+}
+
+impl Registration {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The token the app has to send along with picture uploads.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.disconnect(self.session, self.id);
+    }
+}
+
+/// A random token that is infeasible to guess.
+fn new_token() -> std::io::Result<String> {
+    let mut bytes = [0; 24];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Compares tokens in constant time, so timing doesn't tell how much of a
+/// guessed token was right.
+fn tokens_match(token: &str, guess: &str) -> bool {
+    token.len() == guess.len()
+        && token
+            .bytes()
+            .zip(guess.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn resync(connection: &Connection, session: u64) {
+    let command = EngineCommand {
+        session: Some(session),
+        action: EngineAction::GetState,
+    };
+    if connection.handle.commands.try_send(command).is_err() {
+        tracing::warn!(
+            "couldn't resync connection {}, it is busy",
+            connection.info.id
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_random() {
+        let token = new_token().unwrap();
+        assert_eq!(token.len(), 48);
+        assert_ne!(token, new_token().unwrap());
+    }
+
+    #[test]
+    fn matches_only_equal_tokens() {
+        assert!(tokens_match("abcdef", "abcdef"));
+        assert!(!tokens_match("abcdef", "abcdeg"));
+        assert!(!tokens_match("abcdef", "abcde"));
+        assert!(!tokens_match("abcdef", ""));
+    }
+}
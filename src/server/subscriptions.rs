@@ -0,0 +1,256 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::time::Instant;
+use trainlappcomms::{DetailedLocation, ToApp};
+
+/// Keeps track of which teams' locations an app wants and how often.
+/// Until an app subscribes to something, it gets every location, like before
+/// subscriptions existed, except for teams it unsubscribed from.
+///
+/// Locations of a team arriving within `window` of each other are coalesced, so
+/// only the newest one is sent. If the app asked for batching, all locations
+/// due within a window are sent together as a single `ToApp::Locations`.
+pub struct LocationSubscriptions {
+    /// `None` means everything, otherwise the subscribed teams and their rates
+    teams: Option<HashMap<usize, Option<Duration>>>,
+    /// teams unsubscribed from while receiving everything
+    excluded: HashSet<usize>,
+    window: Duration,
+    batching: bool,
+    /// when the current batch started collecting locations
+    batch_opened: Option<Instant>,
+    last_sent: HashMap<usize, Instant>,
+    /// the newest location of a team that couldn't be sent yet due to its rate
+    pending: HashMap<usize, DetailedLocation>,
+}
+
+impl LocationSubscriptions {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            teams: None,
+            excluded: HashSet::new(),
+            window,
+            batching: false,
+            batch_opened: None,
+            last_sent: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self, teams: Vec<usize>, max_rate: Option<Duration>) {
+        self.excluded.clear();
+        let subscribed = self.teams.get_or_insert_with(HashMap::new);
+        for team in teams {
+            subscribed.insert(team, max_rate);
+        }
+    }
+
+    pub fn unsubscribe(&mut self, teams: Vec<usize>) {
+        for team in teams {
+            match &mut self.teams {
+                Some(subscribed) => {
+                    subscribed.remove(&team);
+                }
+                None => {
+                    self.excluded.insert(team);
+                }
+            }
+            self.pending.remove(&team);
+        }
+    }
+
+    /// Goes back to receiving every location, without any rate limits.
+    pub fn subscribe_all(&mut self) {
+        self.teams = None;
+        self.excluded.clear();
+    }
+
+    pub fn set_batching(&mut self, batching: bool) {
+        self.batching = batching;
+    }
+
+    /// Offers a location of `team` to be sent. Returns the message if it should
+    /// be sent right away. Otherwise it is either dropped or held back until the
+    /// rate allows it, replacing any older location held back for the team.
+    pub fn offer(&mut self, team: usize, location: DetailedLocation) -> Option<ToApp> {
+        let wanted = match &self.teams {
+            Some(teams) => teams.contains_key(&team),
+            None => !self.excluded.contains(&team),
+        };
+        if !wanted {
+            return None;
+        }
+        let now = Instant::now();
+        if self.batching {
+            self.batch_opened.get_or_insert(now);
+            self.pending.insert(team, location);
+            return None;
+        }
+        if let Some(last_sent) = self.last_sent.get(&team) {
+            if now < *last_sent + self.rate(team) {
+                self.pending.insert(team, location);
+                return None;
+            }
+        }
+        self.last_sent.insert(team, now);
+        self.pending.remove(&team);
+        Some(ToApp::Location { team, location })
+    }
+
+    /// When the next held back location is due, if there is one.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let due = self.pending.keys().map(|team| self.due(*team, now)).min()?;
+        match self.batch_opened {
+            Some(opened) if self.batching => Some(due.max(opened + self.window)),
+            _ => Some(due),
+        }
+    }
+
+    /// Removes and returns all held back locations that are due.
+    pub fn take_due(&mut self) -> Vec<ToApp> {
+        let now = Instant::now();
+        let due: Vec<usize> = self
+            .pending
+            .keys()
+            .copied()
+            .filter(|team| self.due(*team, now) <= now)
+            .collect();
+        let locations: Vec<(usize, DetailedLocation)> = due
+            .into_iter()
+            .filter_map(|team| {
+                let location = self.pending.remove(&team)?;
+                self.last_sent.insert(team, now);
+                Some((team, location))
+            })
+            .collect();
+        self.batch_opened = (!self.pending.is_empty()).then_some(now);
+        if self.batching && !locations.is_empty() {
+            vec![ToApp::Locations(locations)]
+        } else {
+            locations
+                .into_iter()
+                .map(|(team, location)| ToApp::Location { team, location })
+                .collect()
+        }
+    }
+
+    /// When the location held back for `team` may be sent, `now` if nothing
+    /// was sent for the team yet.
+    fn due(&self, team: usize, now: Instant) -> Instant {
+        match self.last_sent.get(&team) {
+            Some(last_sent) => *last_sent + self.rate(team),
+            None => now,
+        }
+    }
+
+    fn rate(&self, team: usize) -> Duration {
+        let subscribed = self
+            .teams
+            .as_ref()
+            .and_then(|teams| *teams.get(&team)?)
+            .unwrap_or_default();
+        subscribed.max(self.window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(500);
+
+    fn location(timestamp: i64) -> DetailedLocation {
+        DetailedLocation {
+            latitude: 47.0,
+            longitude: 8.0,
+            accuracy: 10,
+            heading: 0.0,
+            speed: 0.0,
+            timestamp,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_first_location_right_away() {
+        let mut subscriptions = LocationSubscriptions::new(WINDOW);
+        assert!(matches!(
+            subscriptions.offer(1, location(0)),
+            Some(ToApp::Location { team: 1, .. })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coalesces_within_window() {
+        let mut subscriptions = LocationSubscriptions::new(WINDOW);
+        assert!(subscriptions.offer(1, location(0)).is_some());
+        assert!(subscriptions.offer(1, location(1)).is_none());
+        assert!(subscriptions.offer(1, location(2)).is_none());
+        assert!(subscriptions.take_due().is_empty());
+        tokio::time::sleep_until(subscriptions.next_deadline().unwrap()).await;
+        let sent = subscriptions.take_due();
+        assert!(matches!(
+            sent.as_slice(),
+            [ToApp::Location { team: 1, location }] if location.timestamp == 2
+        ));
+        assert!(subscriptions.next_deadline().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batches_fresh_subscription() {
+        let mut subscriptions = LocationSubscriptions::new(WINDOW);
+        subscriptions.set_batching(true);
+        assert!(subscriptions.offer(1, location(0)).is_none());
+        assert!(subscriptions.offer(2, location(1)).is_none());
+        let deadline = subscriptions.next_deadline().unwrap();
+        assert!(deadline <= Instant::now() + WINDOW);
+        tokio::time::sleep_until(deadline).await;
+        let sent = subscriptions.take_due();
+        let [ToApp::Locations(locations)] = sent.as_slice() else {
+            panic!("expected one batch, got {:?}", sent);
+        };
+        assert_eq!(locations.len(), 2);
+        assert!(subscriptions.next_deadline().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn respects_max_rate() {
+        let mut subscriptions = LocationSubscriptions::new(WINDOW);
+        subscriptions.subscribe(vec![1], Some(Duration::from_secs(5)));
+        assert!(subscriptions.offer(1, location(0)).is_some());
+        assert!(subscriptions.offer(1, location(1)).is_none());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(subscriptions.take_due().is_empty());
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert_eq!(subscriptions.take_due().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unsubscribing_keeps_other_teams() {
+        let mut subscriptions = LocationSubscriptions::new(WINDOW);
+        subscriptions.unsubscribe(vec![1]);
+        assert!(subscriptions.offer(1, location(0)).is_none());
+        assert!(subscriptions.offer(2, location(0)).is_some());
+        subscriptions.subscribe_all();
+        assert!(subscriptions.offer(1, location(1)).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subscribe_all_drops_subscriptions() {
+        let mut subscriptions = LocationSubscriptions::new(WINDOW);
+        subscriptions.subscribe(vec![1], None);
+        subscriptions.unsubscribe(vec![1]);
+        assert!(subscriptions.offer(1, location(0)).is_none());
+        assert!(subscriptions.offer(2, location(0)).is_none());
+        subscriptions.subscribe_all();
+        assert!(subscriptions.offer(2, location(1)).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_teams_not_subscribed_to() {
+        let mut subscriptions = LocationSubscriptions::new(WINDOW);
+        subscriptions.subscribe(vec![1], None);
+        assert!(subscriptions.offer(2, location(0)).is_none());
+        assert!(subscriptions.next_deadline().is_none());
+    }
+}
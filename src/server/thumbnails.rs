@@ -0,0 +1,180 @@
+use super::config::Config;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use tokio::sync::OwnedMutexGuard;
+use trainlappcomms::ThumbnailSize;
+
+/// Thumbnails are never made larger than this, whatever an app asks for.
+pub const MAX_DIMENSION: u32 = 2048;
+
+/// Thumbnails a single request may ask for, since each one not cached yet
+/// means decoding a full picture.
+pub const MAX_PER_REQUEST: usize = 64;
+
+type Key = (u64, ThumbnailSize);
+
+/// Thumbnails resized for apps, by picture id and size. Pictures never change,
+/// so entries only go away when the cache is full, least recently used first.
+pub struct Thumbnails {
+    cache: Mutex<Cache>,
+    max_bytes: usize,
+    /// thumbnails being resized right now, see `Thumbnails::lock`
+    resizing: Mutex<HashMap<Key, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+struct Cache {
+    thumbnails: HashMap<Key, (Vec<u8>, u64)>,
+    bytes: usize,
+    clock: u64,
+}
+
+static THUMBNAILS: OnceLock<Thumbnails> = OnceLock::new();
+
+impl Thumbnails {
+    pub fn get() -> &'static Thumbnails {
+        THUMBNAILS.get_or_init(|| Thumbnails::new(Config::get().pictures.thumbnail_cache_bytes))
+    }
+
+    fn new(max_bytes: usize) -> Self {
+        Thumbnails {
+            cache: Mutex::new(Cache {
+                thumbnails: HashMap::new(),
+                bytes: 0,
+                clock: 0,
+            }),
+            max_bytes,
+            resizing: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Waits until nobody else is resizing `picture` to `size`, so concurrent
+    /// requests for the same thumbnail only resize it once. Check the cache
+    /// again after getting the lock.
+    pub async fn lock(&'static self, picture: u64, size: ThumbnailSize) -> ResizeLock {
+        let mutex = self
+            .resizing
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry((picture, size))
+            .or_default()
+            .clone();
+        ResizeLock {
+            guard: Some(mutex.lock_owned().await),
+            key: (picture, size),
+            thumbnails: self,
+        }
+    }
+
+    pub fn thumbnail(&self, picture: u64, size: ThumbnailSize) -> Option<Vec<u8>> {
+        let mut cache = self.cache();
+        cache.clock += 1;
+        let now = cache.clock;
+        let (data, used) = cache.thumbnails.get_mut(&(picture, size))?;
+        *used = now;
+        Some(data.clone())
+    }
+
+    pub fn insert(&self, picture: u64, size: ThumbnailSize, data: Vec<u8>) {
+        let max_bytes = self.max_bytes;
+        if data.len() > max_bytes {
+            return;
+        }
+        let mut cache = self.cache();
+        cache.clock += 1;
+        let now = cache.clock;
+        cache.bytes += data.len();
+        if let Some((old, _)) = cache.thumbnails.insert((picture, size), (data, now)) {
+            cache.bytes -= old.len();
+        }
+        while cache.bytes > max_bytes {
+            let Some(oldest) = cache
+                .thumbnails
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            if let Some((evicted, _)) = cache.thumbnails.remove(&oldest) {
+                cache.bytes -= evicted.len();
+            }
+        }
+    }
+}
+
+pub struct ResizeLock {
+    guard: Option<OwnedMutexGuard<()>>,
+    key: Key,
+    thumbnails: &'static Thumbnails,
+}
+
+impl Drop for ResizeLock {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut resizing = self
+            .thumbnails
+            .resizing
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        // only forget the lock if nobody else is waiting for it
+        if resizing
+            .get(&self.key)
+            .is_some_and(|mutex| Arc::strong_count(mutex) == 1)
+        {
+            resizing.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trainlappcomms::ThumbnailFormat;
+
+    fn size(width: u32) -> ThumbnailSize {
+        ThumbnailSize {
+            max_width: width,
+            max_height: width,
+            format: ThumbnailFormat::Jpeg,
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let thumbnails = Thumbnails::new(30);
+        thumbnails.insert(1, size(10), vec![0; 10]);
+        thumbnails.insert(2, size(10), vec![0; 10]);
+        assert!(thumbnails.thumbnail(1, size(10)).is_some());
+        thumbnails.insert(3, size(10), vec![0; 15]);
+        assert!(thumbnails.thumbnail(1, size(10)).is_some());
+        assert!(thumbnails.thumbnail(2, size(10)).is_none());
+        assert!(thumbnails.thumbnail(3, size(10)).is_some());
+    }
+
+    #[test]
+    fn keeps_sizes_apart() {
+        let thumbnails = Thumbnails::new(100);
+        thumbnails.insert(1, size(10), vec![0; 10]);
+        assert!(thumbnails.thumbnail(1, size(20)).is_none());
+    }
+
+    #[test]
+    fn skips_thumbnails_larger_than_the_cache() {
+        let thumbnails = Thumbnails::new(10);
+        thumbnails.insert(1, size(10), vec![0; 20]);
+        assert!(thumbnails.thumbnail(1, size(10)).is_none());
+    }
+
+    #[tokio::test]
+    async fn forgets_resize_locks() {
+        let thumbnails: &'static Thumbnails = Box::leak(Box::new(Thumbnails::new(100)));
+        let lock = thumbnails.lock(1, size(10)).await;
+        assert_eq!(thumbnails.resizing.lock().unwrap().len(), 1);
+        drop(lock);
+        assert!(thumbnails.resizing.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,263 @@
+use super::config::PictureLimits;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Instant;
+use trainlappcomms::{ClientError, UploadTarget};
+
+/// Uploads a single player may have going on at once, since each of them
+/// holds up to a whole picture in memory.
+const MAX_UPLOADS_PER_PLAYER: usize = 4;
+
+/// Chunked picture uploads in progress. They outlive connections, so an app
+/// can resume an upload after reconnecting.
+pub struct Uploads {
+    uploads: Mutex<HashMap<u64, Upload>>,
+    next_id: AtomicU64,
+}
+
+struct Upload {
+    session: u64,
+    player: u64,
+    target: UploadTarget,
+    size: usize,
+    data: Vec<u8>,
+    touched: Instant,
+}
+
+/// A completely received upload.
+pub struct Finished {
+    pub target: UploadTarget,
+    pub data: Vec<u8>,
+}
+
+static UPLOADS: OnceLock<Uploads> = OnceLock::new();
+
+impl Uploads {
+    pub fn get() -> &'static Uploads {
+        UPLOADS.get_or_init(Uploads::new)
+    }
+
+    fn new() -> Self {
+        Uploads {
+            uploads: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// The uploads, without those that expired.
+    fn uploads(&self, limits: &PictureLimits) -> MutexGuard<'_, HashMap<u64, Upload>> {
+        let mut uploads = self.uploads.lock().unwrap_or_else(|e| e.into_inner());
+        uploads.retain(|_, u| u.touched.elapsed() < limits.upload_expiry);
+        uploads
+    }
+
+    /// A fresh upload id, for answering requests that fail before an upload exists.
+    pub fn new_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Starts an upload, or continues the one with id `resume` if it still
+    /// exists and matches. Returns the id and how many bytes were received.
+    pub fn begin(
+        &self,
+        session: u64,
+        player: u64,
+        resume: Option<u64>,
+        target: UploadTarget,
+        size: u64,
+        limits: &PictureLimits,
+    ) -> Result<(u64, u64), ClientError> {
+        let mut uploads = self.uploads(limits);
+        if let Some((id, upload)) = resume.and_then(|id| uploads.get_mut(&id).map(|u| (id, u))) {
+            if upload.player == player
+                && upload.session == session
+                && upload.target == target
+                && upload.size as u64 == size
+            {
+                upload.touched = Instant::now();
+                return Ok((id, upload.data.len() as u64));
+            }
+        }
+        if size > limits.max_bytes as u64 {
+            return Err(ClientError::PictureRejected(format!(
+                "the picture has {} bytes, at most {} are allowed",
+                size, limits.max_bytes
+            )));
+        }
+        if uploads.values().filter(|u| u.player == player).count() >= MAX_UPLOADS_PER_PLAYER {
+            return Err(ClientError::TooRapid);
+        }
+        let id = self.new_id();
+        uploads.insert(
+            id,
+            Upload {
+                session,
+                player,
+                target,
+                size: size as usize,
+                data: Vec::new(),
+                touched: Instant::now(),
+            },
+        );
+        Ok((id, 0))
+    }
+
+    /// Appends a chunk and returns how many bytes were received. Chunks that
+    /// don't start where the last one ended are ignored, the app can tell from
+    /// the returned count where to continue. If the chunk is rejected, the
+    /// upload is removed, since it can't be finished anymore.
+    pub fn chunk(
+        &self,
+        player: u64,
+        upload_id: u64,
+        offset: u64,
+        data: &[u8],
+        limits: &PictureLimits,
+    ) -> Result<u64, ClientError> {
+        let mut uploads = self.uploads(limits);
+        let upload = find(&mut uploads, player, upload_id)?;
+        upload.touched = Instant::now();
+        if offset == upload.data.len() as u64 {
+            if upload.data.len() + data.len() > upload.size {
+                uploads.remove(&upload_id);
+                return Err(ClientError::BadData(
+                    "the upload is larger than announced".into(),
+                ));
+            }
+            upload.data.extend_from_slice(data);
+        }
+        Ok(upload.data.len() as u64)
+    }
+
+    /// Removes a completely received upload.
+    pub fn finish(
+        &self,
+        player: u64,
+        upload_id: u64,
+        limits: &PictureLimits,
+    ) -> Result<Finished, ClientError> {
+        let mut uploads = self.uploads(limits);
+        let upload = find(&mut uploads, player, upload_id)?;
+        if upload.data.len() < upload.size {
+            return Err(ClientError::BadData(format!(
+                "only {} of {} bytes were uploaded",
+                upload.data.len(),
+                upload.size
+            )));
+        }
+        let upload = uploads.remove(&upload_id).expect("upload was just found");
+        Ok(Finished {
+            target: upload.target,
+            data: upload.data,
+        })
+    }
+}
+
+fn find(
+    uploads: &mut HashMap<u64, Upload>,
+    player: u64,
+    upload_id: u64,
+) -> Result<&mut Upload, ClientError> {
+    uploads
+        .get_mut(&upload_id)
+        .filter(|u| u.player == player)
+        .ok_or_else(|| ClientError::NotFound(format!("upload {}", upload_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::config::Config;
+    use super::*;
+    use std::time::Duration;
+
+    fn limits() -> PictureLimits {
+        PictureLimits {
+            max_bytes: 100,
+            upload_expiry: Duration::from_secs(60),
+            ..Config::get().pictures.clone()
+        }
+    }
+
+    #[test]
+    fn uploads_in_chunks() {
+        let uploads = Uploads::new();
+        let limits = limits();
+        let target = UploadTarget::PlayerProfile;
+        let (id, received) = uploads.begin(1, 2, None, target, 6, &limits).unwrap();
+        assert_eq!(received, 0);
+        assert_eq!(uploads.chunk(2, id, 0, b"abc", &limits).ok(), Some(3));
+        // a repeated chunk is ignored
+        assert_eq!(uploads.chunk(2, id, 0, b"abc", &limits).ok(), Some(3));
+        assert!(uploads.finish(2, id, &limits).is_err());
+        assert_eq!(uploads.chunk(2, id, 3, b"def", &limits).ok(), Some(6));
+        let finished = uploads.finish(2, id, &limits).unwrap();
+        assert_eq!(finished.data, b"abcdef");
+        assert!(uploads.finish(2, id, &limits).is_err());
+    }
+
+    #[test]
+    fn resumes_matching_upload() {
+        let uploads = Uploads::new();
+        let limits = limits();
+        let target = UploadTarget::TeamProfile;
+        let (id, _) = uploads.begin(1, 2, None, target, 6, &limits).unwrap();
+        uploads.chunk(2, id, 0, b"abc", &limits).unwrap();
+        assert_eq!(
+            uploads.begin(1, 2, Some(id), target, 6, &limits).ok(),
+            Some((id, 3))
+        );
+        // another player can't take over the upload
+        let (other, received) = uploads.begin(1, 3, Some(id), target, 6, &limits).unwrap();
+        assert_ne!(other, id);
+        assert_eq!(received, 0);
+        assert!(uploads.chunk(3, id, 3, b"def", &limits).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_uploads() {
+        let uploads = Uploads::new();
+        let limits = limits();
+        let target = UploadTarget::PlayerProfile;
+        assert!(uploads.begin(1, 2, None, target, 101, &limits).is_err());
+        let (id, _) = uploads.begin(1, 2, None, target, 4, &limits).unwrap();
+        assert!(uploads.chunk(2, id, 0, b"abcdef", &limits).is_err());
+        // the upload is gone after that
+        assert!(matches!(
+            uploads.chunk(2, id, 0, b"abcd", &limits),
+            Err(ClientError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn limits_uploads_per_player() {
+        let uploads = Uploads::new();
+        let limits = limits();
+        let target = UploadTarget::PlayerProfile;
+        for _ in 0..MAX_UPLOADS_PER_PLAYER {
+            uploads.begin(1, 2, None, target, 4, &limits).unwrap();
+        }
+        assert!(matches!(
+            uploads.begin(1, 2, None, target, 4, &limits),
+            Err(ClientError::TooRapid)
+        ));
+        assert!(uploads.begin(1, 3, None, target, 4, &limits).is_ok());
+    }
+
+    #[test]
+    fn forgets_expired_uploads() {
+        let uploads = Uploads::new();
+        let limits = limits();
+        let target = UploadTarget::PlayerProfile;
+        let (id, _) = uploads.begin(1, 2, None, target, 4, &limits).unwrap();
+        let expired = PictureLimits {
+            upload_expiry: Duration::ZERO,
+            ..limits
+        };
+        assert!(matches!(
+            uploads.chunk(2, id, 0, b"abcd", &expired),
+            Err(ClientError::NotFound(_))
+        ));
+        assert!(uploads.uploads.lock().unwrap().is_empty());
+    }
+}
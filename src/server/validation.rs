@@ -0,0 +1,235 @@
+use super::config::LocationLimits;
+use super::log_file;
+use geo::{HaversineDistance, Point};
+use trainlappcomms::{ClientError, DetailedLocation};
+
+/// Checks the locations sent by a single app. Obviously broken data is rejected,
+/// implausible movement is only flagged, since gps does weird things on trains
+/// and a game master should have the final say.
+pub struct LocationValidator {
+    limits: &'static LocationLimits,
+    player_id: u64,
+    last: Option<DetailedLocation>,
+}
+
+impl LocationValidator {
+    pub fn new(limits: &'static LocationLimits, player_id: u64) -> Self {
+        Self {
+            limits,
+            player_id,
+            last: None,
+        }
+    }
+
+    pub fn check(&mut self, location: &DetailedLocation) -> Result<(), ClientError> {
+        sanity_check(location, self.limits)?;
+        if let Some(reason) = self.track(location) {
+            self.flag(reason);
+        }
+        Ok(())
+    }
+
+    /// Compares `location` to the last fix that was compared and returns why
+    /// the movement in between is implausible, if it is.
+    fn track(&mut self, location: &DetailedLocation) -> Option<String> {
+        let mut reason = None;
+        if let Some(last) = &self.last {
+            if location.timestamp <= last.timestamp {
+                // older fixes can't be compared meaningfully, keep the newest one
+                return None;
+            }
+            let interval = (location.timestamp - last.timestamp) as u128;
+            if interval < self.limits.min_check_interval.as_millis() {
+                // compare against the older fix later, so frequent fixes still
+                // get checked over a long enough interval
+                return None;
+            }
+            reason = self.check_speed(last, location);
+        }
+        self.last = Some(location.clone());
+        reason
+    }
+
+    /// Why moving from `last` to `location` is implausible, if it is.
+    fn check_speed(&self, last: &DetailedLocation, location: &DetailedLocation) -> Option<String> {
+        let seconds = (location.timestamp - last.timestamp) as f64 / 1000.0;
+        let metres = Point::new(last.longitude as f64, last.latitude as f64).haversine_distance(
+            &Point::new(location.longitude as f64, location.latitude as f64),
+        );
+        let speed = metres / seconds;
+        if speed > self.limits.max_speed as f64 {
+            Some(format!(
+                "moved {:.0} m in {:.1} s ({:.1} m/s) from {},{} to {},{}",
+                metres,
+                seconds,
+                speed,
+                last.latitude,
+                last.longitude,
+                location.latitude,
+                location.longitude
+            ))
+        } else if location.speed > self.limits.max_speed {
+            Some(format!(
+                "reported a speed of {:.1} m/s at {},{}",
+                location.speed, location.latitude, location.longitude
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn flag(&self, reason: String) {
+        let line = format!(
+            "{} player {} {}",
+            chrono::Utc::now().to_rfc3339(),
+            self.player_id,
+            reason
+        );
+        tracing::warn!(player = self.player_id, "flagged: {}", reason);
+        if let Some(path) = self.limits.flag_log.as_deref() {
+            log_file::append(path, line);
+        }
+    }
+}
+
+/// Timestamps below this can't be in milliseconds, they are probably in seconds.
+/// It is in september 2001, long before anyone played this.
+const MIN_TIMESTAMP_MILLIS: i64 = 1_000_000_000_000;
+
+/// Rejects locations that cannot possibly be real, independent of any history.
+/// Timestamps are unix timestamps in milliseconds, ones that look like seconds
+/// are rejected.
+pub fn sanity_check(
+    location: &DetailedLocation,
+    limits: &LocationLimits,
+) -> Result<(), ClientError> {
+    let bad = |text: &str| Err(ClientError::BadData(format!("location {}", text)));
+    if !(location.latitude.is_finite()
+        && location.longitude.is_finite()
+        && location.heading.is_finite()
+        && location.speed.is_finite())
+    {
+        return bad("contains values that aren't finite");
+    }
+    if !(-90.0..=90.0).contains(&location.latitude) {
+        return bad("has a latitude outside of -90..=90");
+    }
+    if !(-180.0..=180.0).contains(&location.longitude) {
+        return bad("has a longitude outside of -180..=180");
+    }
+    if location.timestamp < MIN_TIMESTAMP_MILLIS {
+        return bad("has no valid timestamp in milliseconds");
+    }
+    let latest = chrono::Utc::now().timestamp_millis() + limits.max_future.as_millis() as i64;
+    if location.timestamp > latest {
+        return bad("has a timestamp in the future");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limits() -> &'static LocationLimits {
+        Box::leak(Box::new(LocationLimits {
+            max_speed: 70.0,
+            max_future: Duration::from_secs(60),
+            min_check_interval: Duration::from_secs(5),
+            flag_log: None,
+        }))
+    }
+
+    /// A location `north` metres north of a fixed point, `secs` seconds after a
+    /// fixed time.
+    fn location(north: f32, secs: i64) -> DetailedLocation {
+        DetailedLocation {
+            latitude: 47.0 + north / 111_195.0,
+            longitude: 8.0,
+            accuracy: 5,
+            heading: 0.0,
+            speed: 0.0,
+            timestamp: 1_717_243_200_000 + secs * 1000,
+        }
+    }
+
+    #[test]
+    fn accepts_plausible_locations() {
+        assert!(sanity_check(&location(0.0, 0), limits()).is_ok());
+    }
+
+    #[test]
+    fn rejects_broken_locations() {
+        let limits = limits();
+        let mut broken = location(0.0, 0);
+        broken.latitude = f32::NAN;
+        assert!(sanity_check(&broken, limits).is_err());
+        let mut broken = location(0.0, 0);
+        broken.latitude = 91.0;
+        assert!(sanity_check(&broken, limits).is_err());
+        let mut broken = location(0.0, 0);
+        broken.longitude = -181.0;
+        assert!(sanity_check(&broken, limits).is_err());
+    }
+
+    #[test]
+    fn rejects_timestamps_in_seconds() {
+        let mut location = location(0.0, 0);
+        location.timestamp /= 1000;
+        assert!(sanity_check(&location, limits()).is_err());
+    }
+
+    #[test]
+    fn rejects_timestamps_in_the_future() {
+        let mut location = location(0.0, 0);
+        location.timestamp = chrono::Utc::now().timestamp_millis() + 120_000;
+        assert!(sanity_check(&location, limits()).is_err());
+    }
+
+    #[test]
+    fn flags_implausible_speed() {
+        let validator = LocationValidator::new(limits(), 1);
+        // 100 m/s
+        assert!(validator
+            .check_speed(&location(0.0, 0), &location(1000.0, 10))
+            .is_some());
+        // 20 m/s
+        assert!(validator
+            .check_speed(&location(0.0, 0), &location(200.0, 10))
+            .is_none());
+        let mut reported = location(200.0, 10);
+        reported.speed = 80.0;
+        assert!(validator
+            .check_speed(&location(0.0, 0), &reported)
+            .is_some());
+    }
+
+    #[test]
+    fn skips_speed_over_short_intervals() {
+        let mut validator = LocationValidator::new(limits(), 1);
+        assert!(validator.track(&location(0.0, 0)).is_none());
+        assert!(validator.track(&location(500.0, 1)).is_none());
+    }
+
+    #[test]
+    fn flags_frequent_fixes_over_an_impossible_distance() {
+        let mut validator = LocationValidator::new(limits(), 1);
+        // 250 m/s, in fixes every 2 seconds
+        let flagged = (0..10)
+            .filter_map(|i| validator.track(&location(i as f32 * 500.0, i * 2)))
+            .count();
+        assert!(flagged > 0);
+    }
+
+    #[test]
+    fn keeps_newest_location() {
+        let mut validator = LocationValidator::new(limits(), 1);
+        validator.check(&location(0.0, 10)).unwrap();
+        validator.check(&location(5000.0, 5)).unwrap();
+        assert_eq!(
+            validator.last.as_ref().unwrap().timestamp,
+            location(0.0, 10).timestamp
+        );
+    }
+}
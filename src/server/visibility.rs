@@ -0,0 +1,322 @@
+use super::config::VisibilityRules;
+use std::collections::{HashMap, HashSet};
+use trainlappcomms::{DetailedLocation, Everything, MinimalLocation, State, ToApp};
+
+/// Decides what a single app gets to see of the other teams' locations.
+/// It learns about the roles of all teams from the states passing through it.
+pub struct VisibilityFilter {
+    rules: &'static VisibilityRules,
+    own_team: usize,
+    state: State,
+    runners: HashSet<usize>,
+    /// per team, the reveal slot and location that was last shown
+    revealed: HashMap<usize, (i64, DetailedLocation)>,
+}
+
+impl VisibilityFilter {
+    pub fn new(rules: &'static VisibilityRules, everything: &Everything) -> Self {
+        let mut filter = Self {
+            rules,
+            own_team: everything.your_team,
+            state: everything.state,
+            runners: HashSet::new(),
+            revealed: HashMap::new(),
+        };
+        filter.update(everything);
+        filter
+    }
+
+    fn update(&mut self, everything: &Everything) {
+        self.own_team = everything.your_team;
+        self.state = everything.state;
+        self.runners = everything
+            .teams
+            .iter()
+            .filter(|t| !t.is_catcher)
+            .map(|t| t.id)
+            .collect();
+    }
+
+    /// Whether the location of `team` is restricted for this app.
+    fn is_hidden(&self, team: usize) -> bool {
+        self.state == State::Catcher && team != self.own_team && self.runners.contains(&team)
+    }
+
+    /// Reveals happen in fixed slots of wall clock time, so all catchers
+    /// get to see a runner at the same moment.
+    fn reveal_slot(&self, timestamp: i64) -> Option<i64> {
+        self.rules
+            .reveal_interval
+            .map(|interval| timestamp / (interval.as_millis().max(1) as i64))
+    }
+
+    fn degrade(&self, mut location: DetailedLocation) -> DetailedLocation {
+        if let Some(precision) = self.rules.precision {
+            (location.latitude, location.longitude) =
+                round_position(location.latitude, location.longitude, precision);
+            location.accuracy = location.accuracy.max(precision as u16);
+            location.heading = 0.0;
+            location.speed = 0.0;
+        }
+        location
+    }
+
+    /// Applies the visibility rules to a message. Returns `None` if the message
+    /// shouldn't be sent at all.
+    pub fn filter(&mut self, message: ToApp) -> Option<ToApp> {
+        self.filter_at(message, chrono::Utc::now().timestamp_millis())
+    }
+
+    /// Like `filter`, as if it was `now`, a unix timestamp in milliseconds.
+    fn filter_at(&mut self, mut message: ToApp, now: i64) -> Option<ToApp> {
+        if let Some(everything) = message.everything_mut() {
+            self.update(everything);
+            for team in everything.teams.iter_mut() {
+                if self.is_hidden(team.id) {
+                    team.location = if self.rules.reveal_interval.is_some() {
+                        self.revealed.get(&team.id).map(|(_, l)| l.clone())
+                    } else {
+                        team.location.take().map(|l| self.degrade(l))
+                    };
+                }
+            }
+            return Some(message);
+        }
+        match message {
+            ToApp::Location { team, location } if self.is_hidden(team) => {
+                let location = self.degrade(location);
+                if let Some(slot) = self.reveal_slot(now) {
+                    if self.revealed.get(&team).is_some_and(|(s, _)| *s == slot) {
+                        return None;
+                    }
+                    self.revealed.insert(team, (slot, location.clone()));
+                }
+                Some(ToApp::Location { team, location })
+            }
+            ToApp::SendPastLocations { team, locations } if self.is_hidden(team) => {
+                let mut last_slot = None;
+                let locations = locations
+                    .into_iter()
+                    .filter(|l| {
+                        let slot = self.reveal_slot(l.timestamp);
+                        let new_slot = slot.is_none() || slot != last_slot;
+                        last_slot = slot;
+                        new_slot
+                    })
+                    .map(|l| match self.rules.precision {
+                        Some(precision) => {
+                            let (latitude, longitude) =
+                                round_position(l.latitude, l.longitude, precision);
+                            MinimalLocation {
+                                latitude,
+                                longitude,
+                                timestamp: l.timestamp,
+                            }
+                        }
+                        None => l,
+                    })
+                    .collect();
+                Some(ToApp::SendPastLocations { team, locations })
+            }
+            message => Some(message),
+        }
+    }
+}
+
+/// Snaps a position to a grid with a cell size of roughly `precision` metres.
+fn round_position(latitude: f32, longitude: f32, precision: f32) -> (f32, f32) {
+    const METRES_PER_DEGREE: f32 = 111_320.0;
+    let lat_step = precision / METRES_PER_DEGREE;
+    let lon_step = precision / (METRES_PER_DEGREE * latitude.to_radians().cos().max(0.01));
+    (
+        (latitude / lat_step).round() * lat_step,
+        (longitude / lon_step).round() * lon_step,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use trainlappcomms::Team;
+
+    const OWN: usize = 0;
+    const RUNNER: usize = 1;
+    const OTHER_CATCHER: usize = 2;
+
+    fn rules(reveal_secs: Option<u64>, precision: Option<f32>) -> &'static VisibilityRules {
+        Box::leak(Box::new(VisibilityRules {
+            reveal_interval: reveal_secs.map(Duration::from_secs),
+            precision,
+        }))
+    }
+
+    fn team(id: usize, is_catcher: bool) -> Team {
+        Team {
+            is_catcher,
+            name: format!("team {}", id),
+            picture_id: None,
+            id,
+            bounty: 0,
+            points: 0,
+            players: Vec::new(),
+            challenges: Vec::new(),
+            completed_challenges: Vec::new(),
+            colour: (0, 0, 0),
+            location: Some(location(0)),
+            in_grace_period: false,
+            period_id: 0,
+        }
+    }
+
+    /// The state of a game as seen by a member of team `OWN`.
+    fn everything(state: State) -> Everything {
+        Everything {
+            state,
+            teams: vec![
+                team(OWN, state == State::Catcher),
+                team(RUNNER, false),
+                team(OTHER_CATCHER, true),
+            ],
+            events: Vec::new(),
+            you: 1,
+            your_team: OWN,
+            your_session: 1,
+            presence: Vec::new(),
+        }
+    }
+
+    fn location(timestamp: i64) -> DetailedLocation {
+        DetailedLocation {
+            latitude: 47.376_89,
+            longitude: 8.541_69,
+            accuracy: 5,
+            heading: 90.0,
+            speed: 10.0,
+            timestamp,
+        }
+    }
+
+    fn location_of(team: usize, timestamp: i64) -> ToApp {
+        ToApp::Location {
+            team,
+            location: location(timestamp),
+        }
+    }
+
+    #[test]
+    fn catchers_see_runners_once_per_slot() {
+        let mut filter = VisibilityFilter::new(rules(Some(60), None), &everything(State::Catcher));
+        assert!(filter.filter_at(location_of(RUNNER, 0), 60_000).is_some());
+        assert!(filter.filter_at(location_of(RUNNER, 1), 90_000).is_none());
+        assert!(filter.filter_at(location_of(RUNNER, 2), 119_999).is_none());
+        assert!(filter.filter_at(location_of(RUNNER, 3), 120_000).is_some());
+    }
+
+    #[test]
+    fn catchers_always_see_teammates_and_other_catchers() {
+        let mut filter = VisibilityFilter::new(rules(Some(60), None), &everything(State::Catcher));
+        for now in [60_000, 60_001, 60_002] {
+            assert!(filter
+                .filter_at(location_of(OTHER_CATCHER, now), now)
+                .is_some());
+            assert!(filter.filter_at(location_of(OWN, now), now).is_some());
+        }
+    }
+
+    #[test]
+    fn runners_see_everybody() {
+        let mut filter =
+            VisibilityFilter::new(rules(Some(60), Some(500.0)), &everything(State::Runner));
+        for now in [60_000, 60_001] {
+            let Some(ToApp::Location { location, .. }) =
+                filter.filter_at(location_of(RUNNER, now), now)
+            else {
+                panic!("location was filtered");
+            };
+            assert_eq!(location.latitude, 47.376_89);
+        }
+    }
+
+    #[test]
+    fn reduces_precision_to_the_grid() {
+        let mut filter =
+            VisibilityFilter::new(rules(None, Some(500.0)), &everything(State::Catcher));
+        let Some(ToApp::Location { location, .. }) = filter.filter_at(location_of(RUNNER, 0), 0)
+        else {
+            panic!("location was filtered");
+        };
+        let lat_step = 500.0 / 111_320.0;
+        let cells = location.latitude / lat_step;
+        assert!((cells - cells.round()).abs() < 1e-3);
+        assert!((location.latitude - 47.376_89).abs() <= lat_step);
+        assert_eq!(location.accuracy, 500);
+        assert_eq!(location.speed, 0.0);
+        assert_eq!(location.heading, 0.0);
+    }
+
+    #[test]
+    fn round_position_moves_by_at_most_half_a_cell() {
+        let (latitude, longitude) = round_position(47.376_89, 8.541_69, 1000.0);
+        let lat_step = 1000.0 / 111_320.0;
+        let lon_step = lat_step / 47.376_89_f32.to_radians().cos();
+        assert!((latitude - 47.376_89).abs() <= lat_step / 2.0 + 1e-5);
+        assert!((longitude - 8.541_69).abs() <= lon_step / 2.0 + 1e-5);
+        assert_ne!((latitude, longitude), (47.376_89, 8.541_69));
+        assert_eq!(round_position(0.0, 0.0, 1000.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn masks_runners_in_everything() {
+        let mut filter = VisibilityFilter::new(rules(Some(60), None), &everything(State::Catcher));
+        let Some(ToApp::Everything(masked)) =
+            filter.filter_at(ToApp::Everything(everything(State::Catcher)), 0)
+        else {
+            panic!("everything was filtered");
+        };
+        let location = |id| {
+            masked
+                .teams
+                .iter()
+                .find(|t| t.id == id)
+                .unwrap()
+                .location
+                .clone()
+        };
+        // nothing was revealed yet
+        assert!(location(RUNNER).is_none());
+        assert!(location(OWN).is_some());
+        assert!(location(OTHER_CATCHER).is_some());
+
+        filter.filter_at(location_of(RUNNER, 5), 0);
+        let Some(ToApp::Everything(masked)) =
+            filter.filter_at(ToApp::Everything(everything(State::Catcher)), 10)
+        else {
+            panic!("everything was filtered");
+        };
+        let runner = masked.teams.iter().find(|t| t.id == RUNNER).unwrap();
+        assert_eq!(runner.location.as_ref().map(|l| l.timestamp), Some(5));
+    }
+
+    #[test]
+    fn thins_out_past_locations() {
+        let mut filter = VisibilityFilter::new(rules(Some(60), None), &everything(State::Catcher));
+        let past = |timestamp| MinimalLocation {
+            latitude: 47.0,
+            longitude: 8.0,
+            timestamp,
+        };
+        let message = ToApp::SendPastLocations {
+            team: RUNNER,
+            locations: [0, 30_000, 60_000, 61_000, 130_000]
+                .into_iter()
+                .map(past)
+                .collect(),
+        };
+        let Some(ToApp::SendPastLocations { locations, .. }) = filter.filter_at(message, 0) else {
+            panic!("past locations were filtered");
+        };
+        let timestamps: Vec<_> = locations.iter().map(|l| l.timestamp).collect();
+        assert_eq!(timestamps, [0, 60_000, 130_000]);
+    }
+}